@@ -1,175 +1,1269 @@
 use std::{
     char,
-    fs::{read_to_string, File},
-    io::Read,
+    collections::HashMap,
+    fs::{read, read_to_string, File},
+    io::{self, stdin, BufWriter, Cursor, Read, Seek, Write},
+    path::Path,
     process::exit,
     str::FromStr,
+    time::{Duration, Instant},
 };
 
-use ab_glyph::{Font, FontVec, ScaleFont};
-use anyhow::Result;
-use clap::{command, Parser};
-use image::{DynamicImage, GenericImageView, ImageReader, Rgba, RgbaImage};
+use ab_glyph::{point, Font, FontVec, GlyphId, ScaleFont};
+use anyhow::{anyhow, bail, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{
+    builder::{PossibleValuesParser, TypedValueParser},
+    CommandFactory, Parser,
+};
+use env_logger::Env;
+use image::{
+    codecs::{bmp::BmpEncoder, jpeg::JpegEncoder, tiff::TiffEncoder},
+    imageops::{self, FilterType},
+    DynamicImage, GenericImageView, GrayImage, ImageFormat, ImageReader, Luma, Pixel, RgbImage,
+    Rgba, RgbaImage,
+};
 use imageproc::{
-    drawing::{draw_filled_rect_mut, draw_text_mut},
+    drawing::{draw_filled_rect_mut, draw_hollow_rect_mut, draw_text_mut, text_size},
+    edges::canny,
+    geometric_transformations::{rotate_about_center, Interpolation},
+    pixelops::weighted_sum,
     rect::Rect,
 };
-use indicatif::ProgressBar;
-use rand::seq::SliceRandom;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    seq::SliceRandom,
+    Rng, SeedableRng,
+};
 
 fn main() {
+    env_logger::Builder::from_env(Env::default().default_filter_or("info"))
+        .format_timestamp(None)
+        .format_target(false)
+        .init();
+
     let args = Args::parse();
 
+    if args.list_charsets {
+        list_charsets(args.include_non_alpha);
+        exit(0);
+    }
+
+    if !args.charset_sheet.is_empty() {
+        if args.font.is_empty() {
+            error!("The --charset-sheet flag requires a --font");
+            exit(1);
+        }
+        let font = get_font(&args.font, args.font_index).unwrap_or_else(|e| {
+            error!("Unable to read font file: {}", e);
+            exit(1)
+        });
+        let sheet = get_charset_sheet(&font, args.font_size, args.include_non_alpha);
+        if let Err(e) = save_image(&sheet, &args.charset_sheet, None) {
+            error!("Couldn't write to file: {}: {}", args.charset_sheet, e);
+            exit(1);
+        }
+        exit(0);
+    }
+
+    if !args.inputs.is_empty() && !args.filename.is_empty() {
+        error!("You cannot have both an input file and --inputs");
+        exit(1);
+    }
+
     // validate options
     if !args.textfile.is_empty() && !args.character.is_empty() {
-        println!("You cannot have both flags at the same time: --character, --textfile");
-        exit(0);
+        error!("You cannot have both flags at the same time: --character, --textfile");
+        exit(1);
+    }
+    if !args.background.is_empty() && args.auto_background {
+        error!("You cannot have both flags at the same time: --background, --auto-background");
+        exit(1);
+    }
+    if !args.charset_range.is_empty() && !args.custom_charset.is_empty() {
+        error!("You cannot have both flags at the same time: --charset-range, --custom-charset");
+        exit(1);
+    }
+    if args.texture_fill && args.split_channels {
+        error!("You cannot have both flags at the same time: --texture-fill, --split-channels");
+        exit(1);
+    }
+    if args.burn_safe_area && args.safe_area == "none" {
+        error!("The --burn-safe-area flag requires a --safe-area ratio");
+        exit(1);
+    }
+    if args.filename == "-" && args.custom_charset == "-" {
+        error!("Only one of the input image and --custom-charset can be read from stdin");
+        exit(1);
+    }
+    if !(0.0..=1.0).contains(&args.opacity) {
+        error!("The --opacity must be between 0.0 and 1.0");
+        exit(1);
+    }
+    if !args.jitter_rotation.is_finite() || args.jitter_rotation < 0.0 {
+        error!("The --jitter-rotation must be a finite angle of at least 0.0");
+        exit(1);
+    }
+    if args.uppercase && args.lowercase {
+        error!("You cannot have both flags at the same time: --uppercase, --lowercase");
+        exit(1);
     }
 
-    let charset =
-        Charset::from_str(&args.charset).expect("charset is validated during argument parsing");
-    let mut characters = get_characters(charset);
+    if args.cvd_safe && args.cvd.eq_ignore_ascii_case("none") {
+        error!("The --cvd-safe flag requires a --cvd type");
+        exit(1);
+    }
+
+    let mode = Mode::from_str(&args.mode).expect("mode is validated during argument parsing");
+    let output_format =
+        OutputFormat::from_str(&args.format).expect("format is validated during argument parsing");
+    if args.font.is_empty() && mode != Mode::Pixelate {
+        error!("A --font is required unless --mode pixelate is used");
+        exit(1);
+    }
+    if args.font.is_empty() && !args.signature.is_empty() {
+        error!("The --signature flag requires a --font");
+        exit(1);
+    }
+    if mode == Mode::ShapeMatch && (!args.textfile.is_empty() || !args.character.is_empty()) {
+        error!("--mode shape-match cannot be combined with --character or --textfile");
+        exit(1);
+    }
+    if !args.template.is_empty()
+        && (mode == Mode::ShapeMatch || !args.textfile.is_empty() || !args.character.is_empty())
+    {
+        error!("--template cannot be combined with --character, --textfile or --mode shape-match");
+        exit(1);
+    }
+    if args.font.is_empty() && args.charset.eq_ignore_ascii_case("font") {
+        error!("--charset font requires a --font");
+        exit(1);
+    }
+    if !args.charset_frequency.is_empty()
+        && (!args.custom_charset.is_empty()
+            || !args.charset_range.is_empty()
+            || !args.charset_map.is_empty())
+    {
+        error!("--charset-frequency cannot be combined with --custom-charset, --charset-range or --charset-map");
+        exit(1);
+    }
+    if args.charset_frequency == "-" && (args.filename == "-" || args.custom_charset == "-") {
+        error!("Only one of the input image, --custom-charset and --charset-frequency can be read from stdin");
+        exit(1);
+    }
+    if mode == Mode::Pixelate && output_format == OutputFormat::Ass {
+        error!("--mode pixelate draws no glyphs to write with --format ass");
+        exit(1);
+    }
+    if mode == Mode::ShapeMatch && !args.charset_map.is_empty() {
+        error!("--mode shape-match cannot be combined with --charset-map");
+        exit(1);
+    }
 
-    let background_color = (!args.background.is_empty()).then(|| {
-        get_rgba_from_hex(&args.background).unwrap_or_else(|_| {
-            println!("Invalid background color: {}", args.background);
-            exit(0);
+    // load font, which is optional in pixelate mode
+    let font = (!args.font.is_empty()).then(|| {
+        get_font(&args.font, args.font_index).unwrap_or_else(|e| {
+            error!("Unable to read font file: {}", e);
+            exit(1)
         })
     });
 
+    let charset =
+        Charset::from_str(&args.charset).expect("charset is validated during argument parsing");
+    let mut characters = get_characters(charset, args.include_non_alpha, font.as_ref());
+
+    if !args.charset_range.is_empty() {
+        characters = get_range_characters(&args.charset_range, args.include_non_alpha)
+            .unwrap_or_else(|e| {
+                error!("Invalid charset range: {}: {}", args.charset_range, e);
+                exit(1);
+            });
+    }
+
     if !args.custom_charset.is_empty() {
-        characters = read_to_string(args.custom_charset)
+        // "-" reads the charset from stdin
+        let custom_charset = match args.custom_charset.as_str() {
+            "-" => io::read_to_string(stdin()),
+            filename => read_to_string(filename),
+        };
+        characters = custom_charset
             .unwrap_or_else(|e| {
-                println!("Unable to read custom charset file: {}", e);
-                exit(0);
+                error!("Unable to read custom charset file: {}", e);
+                exit(1);
             })
             .trim()
             .chars()
             .collect();
     }
 
-    // open the image and decode it
-    let mut input_image = match ImageReader::open(&args.filename) {
-        Ok(file) => file.decode().unwrap_or_else(|_| {
-            println!("Unsupported image format");
-            exit(0);
-        }),
-        Err(_) => {
-            println!("No such file: {}", &args.filename);
-            exit(0);
+    if !args.exclude.is_empty() {
+        let remaining: Vec<char> = characters
+            .iter()
+            .copied()
+            .filter(|c| !args.exclude.contains(*c))
+            .collect();
+        match remaining.is_empty() {
+            true => warn!("--exclude would remove every character of the charset, ignoring it"),
+            false => characters = remaining,
+        }
+    }
+    // characters without an advance, like the combining marks of a font, would never move
+    // the pen forward
+    let scaled_font = font
+        .as_ref()
+        .filter(|_| mode != Mode::Pixelate)
+        .map(|font| font.as_scaled(args.font_size));
+    let is_drawable = |c: char| {
+        scaled_font
+            .as_ref()
+            .is_none_or(|scaled_font| get_glyph_advance(scaled_font, c) >= 1.0)
+    };
+    characters.retain(|c| is_drawable(*c));
+
+    // an empty custom charset or range leaves nothing to pick from
+    if characters.is_empty() {
+        error!("The charset contains no characters");
+        exit(1);
+    }
+
+    // characters of the corpus are picked as often as they appear in it
+    let character_distribution = match args.charset_frequency.as_str() {
+        "" => None,
+        filename => {
+            // "-" reads the corpus from stdin
+            let corpus = match filename {
+                "-" => io::read_to_string(stdin()),
+                filename => read_to_string(filename),
+            }
+            .unwrap_or_else(|e| {
+                error!("Unable to read corpus file: {}", e);
+                exit(1);
+            });
+            let frequencies = get_character_frequencies(
+                &corpus,
+                args.include_non_alpha,
+                &args.exclude,
+                args.top_n,
+                is_drawable,
+            );
+            if frequencies.is_empty() {
+                error!("The corpus contains no usable characters: {}", filename);
+                exit(1);
+            }
+            characters = frequencies.iter().map(|(c, _)| *c).collect();
+            Some(
+                WeightedIndex::new(frequencies.iter().map(|(_, count)| *count))
+                    .expect("counts are positive"),
+            )
+        }
+    };
+
+    // brightness bands sorted by their lower luminance bound
+    let mut charset_map = match args.charset_map.is_empty() {
+        true => Vec::new(),
+        false => get_charset_map(&args.charset_map, args.include_non_alpha, font.as_ref())
+            .unwrap_or_else(|e| {
+                error!("Invalid charset map: {}: {}", args.charset_map, e);
+                exit(1);
+            }),
+    };
+    for (_, characters) in &mut charset_map {
+        characters.retain(|c| is_drawable(*c));
+        if characters.is_empty() {
+            error!(
+                "Invalid charset map: {}: a charset contains no characters that can be drawn with this font",
+                args.charset_map
+            );
+            exit(1);
+        }
+    }
+
+    let characters = Characters {
+        characters,
+        charset_map,
+        distribution: character_distribution,
+    };
+    let shared_images = get_shared_images(&args).unwrap_or_else(|e| {
+        error!("{}", e);
+        exit(1);
+    });
+
+    if !args.inputs.is_empty() {
+        exit(render_inputs(
+            &args,
+            font.as_ref(),
+            &characters,
+            &shared_images,
+        ));
+    }
+
+    if let Err(e) = render(
+        &args,
+        font.as_ref(),
+        &characters,
+        &shared_images,
+        &args.filename,
+        &args.outfile,
+    ) {
+        error!("{}", e);
+        exit(1);
+    }
+}
+
+// the characters to draw, which are resolved once for all rendered files
+struct Characters {
+    characters: Vec<char>,
+    // brightness bands sorted by their lower luminance bound
+    charset_map: Vec<(f32, Vec<char>)>,
+    // characters of the corpus are picked as often as they appear in it
+    distribution: Option<WeightedIndex<u64>>,
+}
+
+// images given by option, which are decoded once for all rendered files
+struct SharedImages {
+    // the placement mask at its own size
+    mask: Option<GrayImage>,
+    // the template pixel values are indices into the charset
+    template: Option<GrayImage>,
+    // the image to draw on with --append
+    existing_image: Option<DynamicImage>,
+}
+
+fn get_shared_images(args: &Args) -> Result<SharedImages> {
+    let mask = match args.mask.as_str() {
+        "" => None,
+        filename => {
+            let data = read(filename).map_err(|_| anyhow!("No such file: {}", filename))?;
+            let mask = decode_image(&data, filename)
+                .map_err(|e| anyhow!("Unsupported mask format: {}", e))?;
+            Some(mask.to_luma8())
+        }
+    };
+
+    let template = match args.template.as_str() {
+        "" => None,
+        filename => {
+            let data = read(filename).map_err(|_| anyhow!("No such file: {}", filename))?;
+            Some(
+                get_template_indices(&data)
+                    .map_err(|e| anyhow!("Unsupported template format: {}", e))?,
+            )
+        }
+    };
+
+    let existing_image = match args.append.as_str() {
+        "" => None,
+        filename => {
+            let data = read(filename).map_err(|_| anyhow!("No such file: {}", filename))?;
+            Some(
+                decode_image(&data, filename)
+                    .map_err(|e| anyhow!("Unsupported image format: {}", e))?,
+            )
+        }
+    };
+
+    Ok(SharedImages {
+        mask,
+        template,
+        existing_image,
+    })
+}
+
+// renders the input image to the output file
+fn render(
+    args: &Args,
+    font: Option<&FontVec>,
+    characters: &Characters,
+    shared_images: &SharedImages,
+    input: &str,
+    output: &str,
+) -> Result<()> {
+    let Characters {
+        characters,
+        charset_map,
+        distribution: character_distribution,
+    } = characters;
+    let mode = Mode::from_str(&args.mode).expect("mode is validated during argument parsing");
+    let output_format =
+        OutputFormat::from_str(&args.format).expect("format is validated during argument parsing");
+
+    let background_color = (!args.background.is_empty())
+        .then(|| {
+            get_rgba_from_hex(&args.background)
+                .map_err(|_| anyhow!("Invalid background color: {}", args.background))
+        })
+        .transpose()?;
+
+    let glyph_color = (!args.glyph_color.is_empty())
+        .then(|| {
+            get_rgba_from_hex(&args.glyph_color)
+                .map_err(|_| anyhow!("Invalid glyph color: {}", args.glyph_color))
+        })
+        .transpose()?;
+
+    let grid_color = get_rgba_from_hex(&args.grid_color)
+        .map_err(|_| anyhow!("Invalid grid color: {}", args.grid_color))?;
+
+    let shadow_offset = (!args.shadow_offset.is_empty())
+        .then(|| {
+            get_shadow_offset(&args.shadow_offset)
+                .map_err(|_| anyhow!("Invalid shadow offset: {}", args.shadow_offset))
+        })
+        .transpose()?;
+
+    let shadow_color = get_rgba_from_hex(&args.shadow_color)
+        .map_err(|_| anyhow!("Invalid shadow color: {}", args.shadow_color))?;
+
+    let signature_color = (!args.signature_color.is_empty())
+        .then(|| {
+            get_rgba_from_hex(&args.signature_color)
+                .map_err(|_| anyhow!("Invalid signature color: {}", args.signature_color))
+        })
+        .transpose()?;
+    let signature_position = SignaturePosition::from_str(&args.signature_position)
+        .expect("signature position is validated during argument parsing");
+
+    if args.stencil && background_color.is_none() && !args.auto_background {
+        bail!("The --stencil flag requires a --background color");
+    }
+
+    // open the image and decode it, "-" reads the image from stdin
+    let data = match input {
+        "-" => {
+            let mut data = Vec::new();
+            stdin().read_to_end(&mut data).map(|_| data)
+        }
+        filename => read(filename),
+    };
+    let mut input_image = match (data, args.raw.is_empty()) {
+        (Ok(data), true) => {
+            decode_image(&data, input).map_err(|e| anyhow!("Unsupported image format: {}", e))?
+        }
+        // raw input is plain rgb bytes without any header
+        (Ok(data), false) => {
+            let (width, height) = get_dimensions(&args.raw)
+                .map_err(|e| anyhow!("Invalid raw dimensions: {}: {}", args.raw, e))?;
+            let expected_length = width as u64 * height as u64 * 3;
+            if data.len() as u64 != expected_length {
+                bail!(
+                    "Raw input has {} bytes, but {}x{} needs {}",
+                    data.len(),
+                    width,
+                    height,
+                    expected_length
+                );
+            }
+            DynamicImage::ImageRgb8(
+                RgbImage::from_raw(width, height, data).expect("length is checked above"),
+            )
+        }
+        (Err(_), _) => {
+            bail!("No such file: {}", input);
         }
     };
 
+    // repeat the image in a grid of tiles
+    if !args.tile_input.is_empty() {
+        let (columns, rows) = get_dimensions(&args.tile_input)
+            .map_err(|e| anyhow!("Invalid tile count: {}: {}", args.tile_input, e))?;
+        check_image_memory(
+            input_image.width() as u64 * columns as u64,
+            input_image.height() as u64 * rows as u64,
+            args.force,
+            args.quiet,
+        )?;
+        input_image = get_tiled_image(&input_image, columns, rows)
+            .ok_or_else(|| anyhow!("The tiled image would be too large: {}", args.tile_input))?;
+    }
+
+    // resize to the requested dimensions, the missing one keeps the aspect ratio
+    let aspect_ratio = input_image.width() as f32 / input_image.height() as f32;
+    let target_size = match (args.width, args.height) {
+        (Some(width), Some(height)) => Some((width, height)),
+        (Some(width), None) => Some((width, ((width as f32 / aspect_ratio).round() as u32).max(1))),
+        (None, Some(height)) => Some((
+            ((height as f32 * aspect_ratio).round() as u32).max(1),
+            height,
+        )),
+        (None, None) => None,
+    };
     // scale the image if a non-default scale is set
-    if args.scale != 1.0 {
-        input_image = input_image.resize(
+    let scaled_size = (args.scale != 1.0).then(|| {
+        (
             (input_image.width() as f32 * args.scale).round() as u32,
             (input_image.height() as f32 * args.scale).round() as u32,
-            image::imageops::FilterType::Nearest,
-        );
-    }
-
-    // load font
-    let font = get_font(&args.font).unwrap_or_else(|e| {
-        println!("Unable to read font file: {}", e);
-        exit(0)
+        )
     });
 
-    // load text and initialize character iterator
+    // the output image is as large as the resized input image
+    let (output_width, output_height) = target_size
+        .or(scaled_size)
+        .unwrap_or(input_image.dimensions());
+    check_image_memory(
+        output_width as u64,
+        output_height as u64,
+        args.force,
+        args.quiet,
+    )?;
+
+    match (target_size, scaled_size) {
+        (Some((width, height)), _) => {
+            input_image = input_image.resize_exact(width, height, FilterType::Nearest);
+        }
+        (None, Some((width, height))) => {
+            input_image = input_image.resize(width, height, FilterType::Nearest);
+        }
+        (None, None) => (),
+    }
+
+    // load text
     let text = match args.textfile.as_str() {
         "" => String::new(),
-        filename => sanatize_text(read_to_string(filename).unwrap_or_else(|e| {
-            println!("Could not read text file: {}", e);
-            exit(0);
-        })),
+        filename => {
+            let text = sanatize_text(
+                read_to_string(filename).map_err(|e| anyhow!("Could not read text file: {}", e))?,
+            );
+            match (args.uppercase, args.lowercase) {
+                (true, _) => text.to_uppercase(),
+                (_, true) => text.to_lowercase(),
+                _ => text,
+            }
+        }
+    };
+
+    if args.white_balance != "none" {
+        input_image = get_white_balanced_image(&input_image, &args.white_balance);
+    }
+
+    if args.auto_contrast {
+        input_image = get_contrast_stretched_image(&input_image);
+    }
+
+    if let Some(threshold) = args.pixel_sort {
+        input_image = get_pixel_sorted_image(&input_image, threshold);
+    }
+
+    // detect edges for outline mode
+    let edges = match mode {
+        Mode::Outline => Some(canny(&input_image.to_luma8(), args.low, args.high)),
+        Mode::Normal | Mode::ShapeMatch | Mode::Pixelate => None,
+    };
+
+    let SharedImages {
+        mask,
+        template,
+        existing_image,
+    } = shared_images;
+
+    // scale the placement mask to the size of the image
+    let placement_mask = mask.as_ref().map(|mask| {
+        imageops::resize(
+            mask,
+            input_image.width(),
+            input_image.height(),
+            FilterType::Triangle,
+        )
+    });
+
+    // use the average color of the whole image as the background
+    let background_color = match args.auto_background {
+        true => {
+            let average_color =
+                get_average_color(&input_image, args.sample_step, args.legacy_average);
+            Some(Rgba::from([
+                average_color[0],
+                average_color[1],
+                average_color[2],
+                255,
+            ]))
+        }
+        false => background_color,
     };
-    let mut text_chars = text.chars().cycle();
 
     let image_width = input_image.width();
     let image_height = input_image.height();
 
-    let mut output_image = RgbaImage::new(image_width, image_height);
+    let mut rng = get_rng(args.seed);
 
-    if let Some(background_color) = background_color {
-        draw_filled_rect_mut(
-            &mut output_image,
-            Rect::at(0, 0).of_size(image_width, image_height),
-            background_color,
+    // the palette gets its own rng so the characters stay the same with and without it
+    let palette = args
+        .max_colors
+        .map(|max_colors| get_kmeans_palette(&input_image, max_colors, &mut get_rng(args.seed)));
+
+    // pixelate mode fills plain cells without drawing any glyphs
+    let glyph_font = match mode {
+        Mode::Pixelate => None,
+        Mode::Normal | Mode::Outline | Mode::ShapeMatch => font.as_ref(),
+    };
+    let scaled_font = glyph_font.map(|font| font.as_scaled(args.font_size));
+    // the integer layout truncates every advance like older versions did
+    let layout =
+        Layout::from_str(&args.layout).expect("layout is validated during argument parsing");
+    let get_advance = |advance: f32| match layout {
+        Layout::Integer => advance.trunc(),
+        Layout::Float => advance,
+    };
+    let glyph_height = get_advance(match &scaled_font {
+        Some(scaled_font) => scaled_font.height() - scaled_font.line_gap(),
+        None => args.font_size,
+    });
+    let cell_width = args.cell_width.unwrap_or(args.font_size);
+    let get_glyph_width = |glyph: char| {
+        get_advance(match &scaled_font {
+            Some(scaled_font) => get_glyph_advance(scaled_font, glyph),
+            None => cell_width,
+        })
+    };
+    // cells smaller than a pixel would have no pixels to sample
+    if glyph_height < 1.0 || (scaled_font.is_none() && get_advance(cell_width) < 1.0) {
+        bail!("Cells have to be at least 1 pixel wide and high, use a larger --font-size or --cell-width");
+    }
+
+    let text = get_drawable_text(&text, get_glyph_width);
+    if text.is_empty() && !args.textfile.is_empty() {
+        bail!("The text file contains no characters that can be drawn with this font");
+    }
+    let mut text_chars = text.chars().cycle().peekable();
+    let mut character_chars = args.character.chars().cycle();
+
+    // estimate the grid size using the narrowest glyph that can be drawn
+    let candidates: Vec<char> = match (text.is_empty(), args.character.is_empty()) {
+        (false, _) => text.chars().collect(),
+        (true, false) => args.character.chars().collect(),
+        (true, true) => match charset_map.is_empty() {
+            true => characters.clone(),
+            false => charset_map
+                .iter()
+                .flat_map(|(_, characters)| characters.clone())
+                .collect(),
+        },
+    };
+    let narrowest_glyph = candidates
+        .iter()
+        .map(|c| get_glyph_width(*c))
+        .filter(|width| *width >= 1.0)
+        .fold(f32::INFINITY, f32::min);
+    let rows = (image_height as f32 / glyph_height).ceil() as u64;
+    let columns = (image_width as f32 / narrowest_glyph).ceil() as u64;
+    if rows * columns > args.max_cells {
+        bail!(
+            "The image would be rendered with about {} cells, which exceeds --max-cells {}. Try a smaller --scale or a larger --font-size",
+            rows * columns,
+            args.max_cells
         );
     }
 
-    let mut rng = rand::thread_rng();
+    // one template pixel per cell of the estimated grid
+    let template = template.as_ref().map(|template| {
+        imageops::resize(template, columns as u32, rows as u32, FilterType::Nearest)
+    });
+
+    // everything has been validated at this point, so --check stops before rendering
+    if args.check {
+        // subtitles are written as text whatever the extension
+        if output_format == OutputFormat::Image {
+            if let Err(e) = ImageFormat::from_path(output) {
+                bail!("Unsupported output format: {}: {}", output, e);
+            }
+        }
+        println!("OK");
+        println!("image: {}x{}", image_width, image_height);
+        println!("mode: {}", args.mode.to_lowercase());
+        println!("font: {}", args.font);
+        println!("characters: {}", candidates.len());
+        println!("cells: about {}", rows * columns);
+        match output_format {
+            OutputFormat::Image => println!("output: {}", output),
+            OutputFormat::Ass => println!("output: ass"),
+        }
+        return Ok(());
+    }
 
-    let scaled_font = font.as_scaled(args.font_size);
-    let glyph_height = scaled_font.height() - scaled_font.line_gap();
+    // draw on top of an existing image, scaled to the output size if needed
+    let mut output_image = match existing_image {
+        None => {
+            let mut output_image = RgbaImage::new(image_width, image_height);
+            if let Some(background_color) = background_color {
+                draw_filled_rect_mut(
+                    &mut output_image,
+                    Rect::at(0, 0).of_size(image_width, image_height),
+                    background_color,
+                );
+            }
+            output_image
+        }
+        Some(existing_image) => {
+            if existing_image.dimensions() != (image_width, image_height) {
+                if !args.quiet {
+                    warn!(
+                        "Resizing {} from {}x{} to {}x{}",
+                        args.append,
+                        existing_image.width(),
+                        existing_image.height(),
+                        image_width,
+                        image_height
+                    );
+                }
+                imageops::resize(
+                    &existing_image.to_rgba8(),
+                    image_width,
+                    image_height,
+                    FilterType::Triangle,
+                )
+            } else {
+                existing_image.to_rgba8()
+            }
+        }
+    };
 
     let total_lines = input_image.height() / glyph_height.ceil() as u32;
 
-    let progress_bar = ProgressBar::new(total_lines as u64 + 1);
-
-    let mut y = 0;
-    while y < input_image.height() {
-        let mut x = 0;
-        while x < input_image.width() {
-            let glyph = match text_chars.next() {
-                None => match args.character.is_empty() {
-                    // use random character
-                    true => *characters
-                        .choose(&mut rng)
-                        .expect("vec should never be empty"),
-                    false => args.character.chars().next().unwrap(),
+    let progress_bar = match args.quiet {
+        true => ProgressBar::hidden(),
+        false => ProgressBar::new(total_lines as u64 + 1).with_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40} {pos}/{len} rows ({per_sec}, ETA {eta})",
+            )
+            .expect("progress bar template is valid"),
+        ),
+    };
+
+    // glyphs are rasterized once when they are first drawn and reused afterwards
+    let mut glyph_masks: HashMap<char, Option<GlyphMask>> = HashMap::new();
+    let mut mirrored_glyph_masks: HashMap<char, GlyphMask> = HashMap::new();
+
+    // shape matching compares every character of the charset against each cell
+    let luma_image = match mode {
+        Mode::ShapeMatch => Some(input_image.to_luma8()),
+        Mode::Normal | Mode::Outline | Mode::Pixelate => None,
+    };
+    let shape_candidates: Vec<(char, u32)> = match (mode, glyph_font) {
+        (Mode::ShapeMatch, Some(font)) => characters
+            .iter()
+            .map(|c| {
+                get_glyph_mask(&mut glyph_masks, font, args.font_size, args.antialias, *c);
+                (*c, get_glyph_width(*c) as u32)
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    // glyphs are ink on the background, so on dark backgrounds the ink is the light part
+    let ink_is_light = background_color.is_none_or(|color| get_luminance(&color) <= 127.0);
+
+    // cell outlines are drawn after all glyphs so that no glyph covers them
+    let mut grid_cells = Vec::new();
+
+    // cells drawn with the .notdef glyph because the font lacks the character
+    let mut missing_glyph_cells: u64 = 0;
+    let mut drawn_cells: u64 = 0;
+    let mut subtitle_glyphs = Vec::new();
+
+    let start_time = Instant::now();
+    let mut y: f32 = 0.0;
+    let mut row = 0;
+    while (y.round() as u32) < input_image.height() {
+        // stop between rows and keep what has been drawn so far
+        if let Some(time_limit) = args.time_limit {
+            if start_time.elapsed().as_secs_f32() > time_limit {
+                progress_bar.suspend(|| {
+                    warn!(
+                        "Stopped after the --time-limit of {}s, saving the partial image",
+                        time_limit
+                    )
+                });
+                break;
+            }
+        }
+        let (cell_top, cell_height) = get_cell_span(y, glyph_height);
+
+        let mut x: f32 = 0.0;
+        let mut column = 0;
+        let mut previous_text_glyph = None;
+        while (x.round() as u32) < input_image.width() {
+            let cell_left = x.round() as u32;
+
+            // the nth cell of a row uses the nth pixel of the template row, cells past the
+            // template and indices past the charset are picked as usual
+            let template_glyph = template
+                .as_ref()
+                .filter(|template| column < template.width() && row < template.height())
+                .and_then(|template| {
+                    characters
+                        .get(template.get_pixel(column, row)[0] as usize)
+                        .copied()
+                });
+            column += 1;
+
+            let glyph = match &luma_image {
+                // use the character whose shape matches the cell best
+                Some(luma_image) => get_shape_matched_glyph(
+                    luma_image,
+                    cell_left,
+                    cell_top,
+                    cell_height,
+                    &shape_candidates,
+                    &glyph_masks,
+                    ink_is_light,
+                ),
+                // text is only consumed once the glyph is actually drawn
+                None => match text_chars.peek().copied() {
+                    None => match args.character.is_empty() {
+                        // use the template character, or a random one from the band of the
+                        // cell brightness if mapped
+                        true => match (template_glyph, &character_distribution) {
+                            (Some(glyph), _) => glyph,
+                            // frequent characters are picked more often
+                            (None, Some(distribution)) => characters[distribution.sample(&mut rng)],
+                            (None, None) => {
+                                let characters = match charset_map.is_empty() {
+                                    true => characters,
+                                    false => {
+                                        // the glyph width isn't known yet, so a square cell is sampled
+                                        let luminance = get_luminance(&get_average_color(
+                                            &input_image.crop_imm(
+                                                cell_left,
+                                                cell_top,
+                                                cell_height,
+                                                cell_height,
+                                            ),
+                                            args.sample_step,
+                                            args.legacy_average,
+                                        ));
+                                        charset_map
+                                            .iter()
+                                            .rev()
+                                            .find(|(threshold, _)| luminance >= *threshold)
+                                            .map_or(&charset_map[0].1, |(_, characters)| characters)
+                                    }
+                                };
+                                *characters
+                                    .choose(&mut rng)
+                                    .expect("vec should never be empty")
+                            }
+                        },
+                        false => character_chars
+                            .next()
+                            .expect("character is checked to be non-empty"),
+                    },
+                    Some(c) => c,
                 },
-                Some(c) => c,
             };
 
-            let glyph_id = font.glyph_id(glyph);
-            let glyph_width =
-                scaled_font.h_advance(glyph_id) + scaled_font.h_side_bearing(glyph_id);
-            let image_section = input_image.crop_imm(x, y, glyph_width as u32, glyph_height as u32);
-            let color = get_average_color(image_section);
+            x += get_advance(get_kerning(
+                scaled_font.as_ref(),
+                previous_text_glyph.take(),
+                glyph,
+            ));
+            let cell_left = x.round() as u32;
+            if cell_left >= image_width {
+                break;
+            }
+
+            let (Ok(cell_x), Ok(cell_y)) = (i32::try_from(cell_left), i32::try_from(cell_top))
+            else {
+                bail!(
+                    "Image is too large to draw into: {}x{}",
+                    image_width,
+                    image_height
+                );
+            };
+
+            let glyph_width = get_glyph_width(glyph);
+            let (cell_left, cell_width) = get_cell_span(x, glyph_width);
+            if args.debug_grid {
+                grid_cells.push(Rect::at(cell_x, cell_y).of_size(cell_width, cell_height));
+            }
+            let glyph_mask = glyph_font.and_then(|font| {
+                get_glyph_mask(
+                    &mut glyph_masks,
+                    font,
+                    args.font_size,
+                    args.antialias,
+                    glyph,
+                )
+            });
+            // glyphs in the right half are flipped to mirror the left half
+            let glyph_mask = match args.mirror && cell_left >= image_width / 2 {
+                true => glyph_mask.map(|glyph_mask| {
+                    &*mirrored_glyph_masks
+                        .entry(glyph)
+                        .or_insert_with(|| get_mirrored_glyph_mask(glyph_mask, glyph_width))
+                }),
+                false => glyph_mask,
+            };
+
+            // only draw glyphs on cells that contain an edge in outline mode
+            if let Some(edges) = &edges {
+                let has_edge =
+                    imageops::crop_imm(edges, cell_left, cell_top, cell_width, cell_height)
+                        .pixels()
+                        .any(|(_, _, pixel)| pixel[0] > 0);
+                if !has_edge {
+                    x += glyph_width;
+                    continue;
+                }
+            }
+
+            let image_section = input_image.crop_imm(cell_left, cell_top, cell_width, cell_height);
+            let color = match (args.ink_sampled_color, glyph_mask) {
+                (true, Some(glyph_mask)) => get_ink_average_color(&image_section, glyph_mask)
+                    .unwrap_or_else(|| {
+                        get_average_color(&image_section, args.sample_step, args.legacy_average)
+                    }),
+                (_, _) if args.soft_cells => get_soft_average_color(
+                    &input_image,
+                    cell_left,
+                    cell_top,
+                    cell_width,
+                    cell_height,
+                ),
+                _ => get_average_color(&image_section, args.sample_step, args.legacy_average),
+            };
+            let color = match args.hue_from_image {
+                true => get_hue_shifted_color(&color, args.sat, args.light),
+                false => color,
+            };
+            let color = get_posterized_color(&color, args.posterize);
+            let color = match &palette {
+                Some(palette) => get_nearest_palette_color(&color, palette),
+                None => color,
+            };
+            let color = get_cvd_color(&color, &args.cvd, args.cvd_safe);
+
+            // leave cells that are too dark as background
+            if get_luminance(&color) < args.reveal_threshold {
+                x += glyph_width;
+                continue;
+            }
+            // white mask pixels place a glyph, gray ones only with a matching chance
+            if let Some(placement_mask) = &placement_mask {
+                let mask_value = placement_mask.get_pixel(
+                    (cell_left + cell_width / 2).min(image_width - 1),
+                    (cell_top + cell_height / 2).min(image_height - 1),
+                )[0];
+                let is_placed = match mask_value {
+                    0 => false,
+                    255 => true,
+                    value => rng.gen_range(0..255) < value,
+                };
+                if !is_placed {
+                    x += glyph_width;
+                    continue;
+                }
+            }
+            if text_chars.next().is_some() {
+                previous_text_glyph = Some(glyph);
+            }
+            drawn_cells += 1;
+            if glyph_font.is_some_and(|font| font.glyph_id(glyph) == GlyphId(0)) {
+                missing_glyph_cells += 1;
+            }
+
+            // in stencil mode the cell is filled with the image color and the glyph is
+            // punched out of it in the background color
+            let cell_color = match (
+                args.stencil || mode == Mode::Pixelate,
+                args.dynamic_background,
+            ) {
+                (true, _) => Some(color),
+                (false, true) => Some(get_dimmed_color(&color)),
+                (false, false) => None,
+            };
+            let glyph_color = match (glyph_color, args.stencil) {
+                (Some(glyph_color), _) => glyph_color,
+                (None, true) => background_color.expect("stencil requires a background color"),
+                (None, false) => color,
+            };
+            // keep the glyph distinguishable from whatever is drawn behind it
+            let glyph_color = match (args.auto_readability, cell_color.or(background_color)) {
+                (true, Some(behind_color)) => get_readable_color(&glyph_color, &behind_color),
+                _ => glyph_color,
+            };
+            let glyph_color = get_isolated_color(&glyph_color, &args.channel);
 
-            if args.dynamic_background {
-                let dimmed_color = get_dimmed_color(&color);
+            if let Some(cell_color) = cell_color {
                 draw_filled_rect_mut(
                     &mut output_image,
-                    Rect::at(x.try_into().unwrap(), y.try_into().unwrap())
-                        .of_size(glyph_width.round() as u32, glyph_height.round() as u32),
-                    dimmed_color,
+                    Rect::at(cell_x, cell_y).of_size(cell_width, cell_height),
+                    cell_color,
                 );
             }
 
-            draw_text_mut(
-                &mut output_image,
-                color,
-                x.try_into().unwrap(),
-                y.try_into().unwrap(),
-                args.font_size,
-                &font,
-                &glyph.to_string(),
-            );
-            x += glyph_width as u32;
+            // subtitle output only keeps where each glyph goes
+            if output_format == OutputFormat::Ass {
+                subtitle_glyphs.push((
+                    cell_x + args.glyph_offset_x,
+                    cell_y + args.glyph_offset_y,
+                    glyph,
+                    glyph_color,
+                ));
+                x += glyph_width;
+                continue;
+            }
+
+            // rotate each glyph by a random angle around its center
+            let rotated_glyph_mask = match (glyph_mask, args.jitter_rotation > 0.0) {
+                (Some(glyph_mask), true) => {
+                    let degrees = rng.gen_range(-args.jitter_rotation..=args.jitter_rotation);
+                    Some(get_rotated_glyph_mask(glyph_mask, degrees))
+                }
+                _ => None,
+            };
+            let glyph_mask = rotated_glyph_mask.as_ref().or(glyph_mask);
+
+            if let (Some(glyph_mask), Some((shadow_x, shadow_y))) = (glyph_mask, shadow_offset) {
+                draw_glyph_mask(
+                    &mut output_image,
+                    glyph_mask,
+                    cell_x + args.glyph_offset_x + shadow_x,
+                    cell_y + args.glyph_offset_y + shadow_y,
+                    shadow_color,
+                    args.opacity,
+                );
+            }
+
+            match (glyph_mask, args.split_channels) {
+                // draw each channel separately with a horizontal offset
+                (Some(glyph_mask), true) => {
+                    for (channel, offset) in
+                        [(0, -args.split_offset), (1, 0), (2, args.split_offset)]
+                    {
+                        draw_glyph_mask_channel(
+                            &mut output_image,
+                            glyph_mask,
+                            cell_x + args.glyph_offset_x + offset,
+                            cell_y + args.glyph_offset_y,
+                            glyph_color,
+                            channel,
+                            args.opacity,
+                        );
+                    }
+                }
+                // fill the glyph with the source pixels beneath it
+                (Some(glyph_mask), false) if args.texture_fill => draw_textured_glyph_mask(
+                    &mut output_image,
+                    glyph_mask,
+                    cell_x + args.glyph_offset_x,
+                    cell_y + args.glyph_offset_y,
+                    &input_image,
+                    args.opacity,
+                ),
+                (Some(glyph_mask), false) => draw_glyph_mask(
+                    &mut output_image,
+                    glyph_mask,
+                    cell_x + args.glyph_offset_x,
+                    cell_y + args.glyph_offset_y,
+                    glyph_color,
+                    args.opacity,
+                ),
+                (None, _) => (),
+            }
+            x += glyph_width;
         }
-        y += glyph_height as u32;
+        y += glyph_height;
+        row += 1;
         progress_bar.inc(1);
     }
 
-    if output_image.save(&args.outfile).is_err() {
-        println!("Couldn't write to file: {}", args.outfile);
+    // the metrics are written for image and subtitle output alike
+    let write_metrics = |render_duration: Duration| -> Result<()> {
+        if args.metrics_file.is_empty() {
+            return Ok(());
+        }
+        let metrics = get_metrics(&[
+            (
+                "characterize_render_duration_seconds",
+                "Time spent rendering the image.",
+                render_duration.as_secs_f64(),
+            ),
+            (
+                "characterize_cells",
+                "Number of cells that were drawn.",
+                drawn_cells as f64,
+            ),
+            (
+                "characterize_missing_glyph_cells",
+                "Number of cells drawn with a glyph missing from the font.",
+                missing_glyph_cells as f64,
+            ),
+        ]);
+        if let Err(e) = std::fs::write(&args.metrics_file, metrics) {
+            bail!("Couldn't write to file: {}: {}", args.metrics_file, e);
+        }
+        Ok(())
+    };
+
+    if output_format == OutputFormat::Ass {
+        progress_bar.finish();
+        let subtitles = get_ass_subtitles(
+            &subtitle_glyphs,
+            image_width,
+            image_height,
+            &args.font,
+            args.font_size,
+        );
+        if let Err(e) = std::fs::write(output, subtitles) {
+            bail!("Couldn't write to file: {}: {}", output, e);
+        }
+        return write_metrics(start_time.elapsed());
+    }
+
+    for cell in grid_cells {
+        draw_hollow_rect_mut(&mut output_image, cell, grid_color);
+    }
+
+    if let (false, Some(font)) = (args.signature.is_empty(), &font) {
+        draw_signature(
+            &mut output_image,
+            font,
+            args.signature_size,
+            &args.signature,
+            signature_position,
+            signature_color,
+        );
+    }
+
+    // resize the finished image if a non-default output scale is set
+    if args.output_scale != 1.0 {
+        let filter =
+            get_filter_type(&args.filter).expect("filter is validated during argument parsing");
+        let scaled_width =
+            ((output_image.width() as f32 * args.output_scale).round() as u32).max(1);
+        let scaled_height =
+            ((output_image.height() as f32 * args.output_scale).round() as u32).max(1);
+        check_image_memory(
+            scaled_width as u64,
+            scaled_height as u64,
+            args.force,
+            args.quiet,
+        )?;
+        output_image = imageops::resize(&output_image, scaled_width, scaled_height, filter);
+    }
+
+    if args.grain > 0 {
+        add_grain(&mut output_image, args.grain, &mut get_rng(args.seed));
     }
+
+    // outline the central crop that survives the selected aspect ratio
+    let safe_area = get_safe_area(&args.safe_area, output_image.width(), output_image.height());
+    if let (Some(safe_area), true) = (safe_area, args.burn_safe_area) {
+        draw_hollow_rect_mut(&mut output_image, safe_area, grid_color);
+    }
+
     progress_bar.finish();
+    let render_duration = start_time.elapsed();
+
+    if missing_glyph_cells > 0 && !args.quiet {
+        warn!(
+            "{} cells used a missing glyph, the font doesn't cover the whole charset",
+            missing_glyph_cells
+        );
+    }
+
+    let command = get_command(args, input, output);
+    let comment = match (args.comment.is_empty(), args.embed_command) {
+        (true, false) => None,
+        (true, true) => Some(command),
+        (false, false) => Some(args.comment.clone()),
+        (false, true) => Some(format!("{}\n{}", args.comment, command)),
+    };
+
+    if let Err(e) = save_image(&output_image, output, comment.as_deref()) {
+        bail!("Couldn't write to file: {}: {}", output, e);
+    }
+
+    // print the encoded output for embedding in html or markdown
+    if args.data_uri {
+        let format =
+            ImageFormat::from_path(output).expect("the output format was checked when saving");
+        let mut data = Cursor::new(Vec::new());
+        if let Err(e) = write_image(&output_image, &mut data, format, comment.as_deref()) {
+            bail!("Couldn't encode the data URI: {}", e);
+        }
+        println!(
+            "data:{};base64,{}",
+            format.to_mime_type(),
+            STANDARD.encode(data.into_inner())
+        );
+    }
+
+    // write a downscaled proof that fits into --proof-size
+    if args.proof {
+        let filename = get_suffixed_filename(output, "proof");
+        let scale = (args.proof_size as f32
+            / output_image.width().max(output_image.height()) as f32)
+            .min(1.0);
+        let filter =
+            get_filter_type(&args.filter).expect("filter is validated during argument parsing");
+        let proof_width = ((output_image.width() as f32 * scale).round() as u32).max(1);
+        let proof_height = ((output_image.height() as f32 * scale).round() as u32).max(1);
+        check_image_memory(
+            proof_width as u64,
+            proof_height as u64,
+            args.force,
+            args.quiet,
+        )?;
+        let proof_image = imageops::resize(&output_image, proof_width, proof_height, filter);
+        if let Err(e) = save_image(&proof_image, &filename, None) {
+            bail!("Couldn't write to file: {}: {}", filename, e);
+        }
+    }
+
+    // without --burn-safe-area the guide goes into a separate preview image
+    if let (Some(safe_area), false) = (safe_area, args.burn_safe_area) {
+        let filename = get_suffixed_filename(output, "safe_area");
+        let mut preview_image = output_image.clone();
+        draw_hollow_rect_mut(&mut preview_image, safe_area, grid_color);
+        if let Err(e) = save_image(&preview_image, &filename, None) {
+            bail!("Couldn't write to file: {}: {}", filename, e);
+        }
+    }
+
+    // write each color channel as its own grayscale image next to the composite
+    if args.separate_channels {
+        for (channel, suffix) in ["r", "g", "b"].into_iter().enumerate() {
+            let filename = get_suffixed_filename(output, suffix);
+            let channel_image =
+                GrayImage::from_fn(output_image.width(), output_image.height(), |x, y| {
+                    Luma([output_image.get_pixel(x, y)[channel]])
+                });
+            if let Err(e) = channel_image.save(&filename) {
+                bail!("Couldn't write to file: {}: {}", filename, e);
+            }
+        }
+    }
+
+    write_metrics(render_duration)
 }
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[arg(required_unless_present_any = ["list_charsets", "charset_sheet", "inputs"], default_value_t = String::new())]
     filename: String,
+    #[arg(required_unless_present_any = ["list_charsets", "charset_sheet", "inputs"], default_value_t = String::new())]
     outfile: String,
-    #[arg(short, long)]
+    #[arg(long, default_value_t = String::new())]
+    inputs: String,
+    #[arg(short, long, default_value_t = String::new())]
     font: String,
+    #[arg(long, default_value_t = 0)]
+    font_index: u32,
     #[arg(long, default_value_t = 12.0)]
     font_size: f32,
     #[arg(short, long, default_value_t = 1.0)]
     scale: f32,
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    width: Option<u32>,
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    height: Option<u32>,
+    #[arg(long, default_value_t = String::new())]
+    raw: String,
+    #[arg(long, default_value_t = String::new())]
+    tile_input: String,
     #[arg(short, long, default_value_t = String::new())]
     character: String,
     #[arg(long, default_value_t = String::new())]
@@ -190,48 +1284,909 @@ struct Args {
         "binary",
         "braille",
         "playingcards",
+        "tifinagh",
+        "cherokee",
+        "font",
     ])]
     charset: String,
     #[arg(long, default_value_t = String::new())]
     custom_charset: String,
-    #[arg(short, long, default_value_t = String::new())]
-    background: String,
-    #[arg(short, long, default_value_t = false)]
+    #[arg(long, default_value_t = String::new())]
+    charset_range: String,
+    #[arg(long, default_value_t = String::new())]
+    charset_map: String,
+    #[arg(long, default_value_t = String::new())]
+    exclude: String,
+    #[arg(long, default_value_t = String::new())]
+    charset_frequency: String,
+    #[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+    top_n: Option<u64>,
+    #[arg(short, long, default_value_t = String::new())]
+    background: String,
+    #[arg(short, long, default_value_t = false)]
     dynamic_background: bool,
+    #[arg(long, default_value_t = false)]
+    ink_sampled_color: bool,
+    #[arg(long, default_value_t = false)]
+    uppercase: bool,
+    #[arg(long, default_value_t = false)]
+    lowercase: bool,
+    #[arg(long, default_value_t = false)]
+    stencil: bool,
+    #[arg(long, default_value_t = 10_000_000)]
+    max_cells: u64,
+    #[arg(long, default_value_t = 8, value_parser = clap::value_parser!(u8).range(1..=8))]
+    posterize: u8,
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..=256))]
+    max_colors: Option<u32>,
+    #[arg(long, default_value_t = String::new())]
+    signature: String,
+    #[arg(long, default_value_t = String::from("bottom-right"), ignore_case = true, value_parser = [
+        "top-left",
+        "top-right",
+        "bottom-left",
+        "bottom-right",
+    ])]
+    signature_position: String,
+    #[arg(long, default_value_t = 12.0)]
+    signature_size: f32,
+    #[arg(long, default_value_t = String::new())]
+    signature_color: String,
+    #[arg(long, default_value_t = false)]
+    include_non_alpha: bool,
+    #[arg(long, default_value_t = false)]
+    auto_background: bool,
+    #[arg(long, default_value_t = false)]
+    list_charsets: bool,
+    #[arg(long, default_value_t = String::new())]
+    charset_sheet: String,
+    #[arg(long, default_value_t = 1.0)]
+    output_scale: f32,
+    #[arg(long, default_value_t = String::from("lanczos3"), ignore_case = true, value_parser = [
+        "nearest",
+        "triangle",
+        "catmullrom",
+        "gaussian",
+        "lanczos3",
+    ])]
+    filter: String,
+    #[arg(long, default_value_t = String::from("normal"), ignore_case = true, value_parser = [
+        "normal",
+        "outline",
+        "shape-match",
+        "pixelate",
+    ])]
+    mode: String,
+    // ass writes each glyph as a positioned subtitle event instead of an image
+    #[arg(long, default_value_t = String::from("image"), ignore_case = true, value_parser = [
+        "image",
+        "ass",
+    ])]
+    format: String,
+    #[arg(long, default_value_t = 50.0)]
+    low: f32,
+    #[arg(long, default_value_t = 100.0)]
+    high: f32,
+    #[arg(long, default_value_t = String::new())]
+    glyph_color: String,
+    #[arg(long, default_value_t = false)]
+    hue_from_image: bool,
+    #[arg(long, default_value_t = 1.0)]
+    sat: f32,
+    #[arg(long, default_value_t = 0.5)]
+    light: f32,
+    #[arg(short, long, default_value_t = false)]
+    quiet: bool,
+    #[arg(long, default_value_t = false)]
+    force: bool,
+    #[arg(long, default_value_t = false)]
+    check: bool,
+    #[arg(long)]
+    time_limit: Option<f32>,
+    #[arg(long, default_value_t = 1, value_parser = PossibleValuesParser::new(["1", "2", "4"])
+        .map(|level| level.parse::<u32>().expect("level is one of the possible values")))]
+    antialias: u32,
+    #[arg(long, default_value_t = String::from("rgb"), ignore_case = true, value_parser = [
+        "rgb",
+        "r",
+        "g",
+        "b",
+    ])]
+    channel: String,
+    #[arg(long, default_value_t = String::from("none"), ignore_case = true, value_parser = [
+        "none",
+        "protanopia",
+        "deuteranopia",
+        "tritanopia",
+    ])]
+    cvd: String,
+    #[arg(long, default_value_t = false)]
+    cvd_safe: bool,
+    #[arg(long, default_value_t = false)]
+    texture_fill: bool,
+    #[arg(long, default_value_t = false)]
+    split_channels: bool,
+    #[arg(long, default_value_t = 2, allow_negative_numbers = true)]
+    split_offset: i32,
+    #[arg(long, default_value_t = String::new(), allow_hyphen_values = true)]
+    shadow_offset: String,
+    #[arg(long, default_value_t = String::from("#202020"))]
+    shadow_color: String,
+    #[arg(long, default_value_t = 1.0)]
+    opacity: f32,
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    glyph_offset_x: i32,
+    #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+    glyph_offset_y: i32,
+    #[arg(long, default_value_t = 0.0)]
+    jitter_rotation: f32,
+    #[arg(long, default_value_t = false)]
+    mirror: bool,
+    #[arg(long, default_value_t = false)]
+    auto_contrast: bool,
+    #[arg(long, default_value_t = String::from("none"), ignore_case = true, value_parser = [
+        "none",
+        "auto",
+        "gray-world",
+    ])]
+    white_balance: String,
+    #[arg(long, default_value_t = false)]
+    auto_readability: bool,
+    #[arg(long)]
+    pixel_sort: Option<f32>,
+    #[arg(long, default_value_t = 0.0)]
+    reveal_threshold: f32,
+    #[arg(long, default_value_t = String::new())]
+    mask: String,
+    #[arg(long, default_value_t = String::new())]
+    append: String,
+    #[arg(long, default_value_t = String::new())]
+    template: String,
+    #[arg(long)]
+    seed: Option<u64>,
+    #[arg(long, default_value_t = 0)]
+    grain: u8,
+    #[arg(long, default_value_t = false)]
+    debug_grid: bool,
+    #[arg(long, default_value_t = String::from("none"), value_parser = [
+        "none",
+        "1:1",
+        "4:5",
+        "9:16",
+    ])]
+    safe_area: String,
+    #[arg(long, default_value_t = false)]
+    burn_safe_area: bool,
+    #[arg(long, default_value_t = String::from("#ff0000"))]
+    grid_color: String,
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    sample_step: u32,
+    #[arg(long, default_value_t = false)]
+    legacy_average: bool,
+    #[arg(long, default_value_t = false)]
+    soft_cells: bool,
+    #[arg(long, default_value_t = String::new())]
+    comment: String,
+    #[arg(long, default_value_t = false)]
+    embed_command: bool,
+    #[arg(long, default_value_t = false)]
+    separate_channels: bool,
+    #[arg(long, default_value_t = false)]
+    data_uri: bool,
+    #[arg(long, default_value_t = String::new())]
+    metrics_file: String,
+    #[arg(long, default_value_t = false)]
+    proof: bool,
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    proof_size: u32,
+    #[arg(long)]
+    cell_width: Option<f32>,
+    #[arg(long, default_value_t = String::from("integer"), ignore_case = true, value_parser = [
+        "float",
+        "integer",
+    ])]
+    layout: String,
 }
 
-fn get_font(filename: &str) -> Result<FontVec> {
+// text is set with the kerning of the font between consecutive characters
+fn get_kerning<F: Font>(
+    scaled_font: Option<&impl ScaleFont<F>>,
+    previous: Option<char>,
+    glyph: char,
+) -> f32 {
+    match (previous, scaled_font) {
+        (Some(previous), Some(scaled_font)) => {
+            scaled_font.kern(scaled_font.glyph_id(previous), scaled_font.glyph_id(glyph))
+        }
+        _ => 0.0,
+    }
+}
+
+fn get_glyph_advance<F: Font>(scaled_font: &impl ScaleFont<F>, glyph: char) -> f32 {
+    let glyph_id = scaled_font.glyph_id(glyph);
+    scaled_font.h_advance(glyph_id) + scaled_font.h_side_bearing(glyph_id)
+}
+
+// drops characters without an advance, like combining marks and zero width spaces,
+// which would never move the pen forward
+fn get_drawable_text(text: &str, get_glyph_width: impl Fn(char) -> f32) -> String {
+    text.chars()
+        .filter(|c| get_glyph_width(*c) >= 1.0)
+        .collect()
+}
+
+// refuses to allocate huge image buffers unless forced
+fn check_image_memory(width: u64, height: u64, force: bool, quiet: bool) -> Result<()> {
+    const MEMORY_LIMIT: u64 = 1 << 30;
+    let image_bytes = width * height * 4;
+    if image_bytes > MEMORY_LIMIT {
+        let image_gib = image_bytes as f64 / MEMORY_LIMIT as f64;
+        if !force {
+            bail!(
+                "The output image would need about {:.1} GiB of memory. Use --force to render it anyway",
+                image_gib
+            );
+        }
+        if !quiet {
+            warn!(
+                "The output image needs about {:.1} GiB of memory",
+                image_gib
+            );
+        }
+    }
+    Ok(())
+}
+
+// the pen position keeps the fractional advances and is only rounded for drawing,
+// so that rounding errors don't accumulate across the image
+fn get_cell_span(position: f32, advance: f32) -> (u32, u32) {
+    let start = position.round() as u32;
+    (start, (position + advance).round() as u32 - start)
+}
+
+// renders every "input_path output_path" line of the list with the same options and font,
+// so that a failing file doesn't stop the others
+fn render_inputs(
+    args: &Args,
+    font: Option<&FontVec>,
+    characters: &Characters,
+    shared_images: &SharedImages,
+) -> i32 {
+    let list = read_to_string(&args.inputs).unwrap_or_else(|e| {
+        error!("Unable to read input list: {}: {}", args.inputs, e);
+        exit(1);
+    });
+
+    let mut total = 0;
+    let mut failed = 0;
+    for (number, line) in list.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let paths: Vec<&str> = line.split_whitespace().collect();
+        let [input, output] = paths[..] else {
+            error!(
+                "Line {} should be \"input_path output_path\": {}",
+                number + 1,
+                line
+            );
+            total += 1;
+            failed += 1;
+            continue;
+        };
+
+        total += 1;
+        match render(args, font, characters, shared_images, input, output) {
+            Ok(()) => info!("Rendered {} to {}", input, output),
+            Err(e) => {
+                error!("Failed to render {}: {}", input, e);
+                failed += 1;
+            }
+        }
+    }
+
+    info!("Rendered {} of {} files", total - failed, total);
+    match failed {
+        0 => 0,
+        _ => 1,
+    }
+}
+
+// the command line doesn't include the program path to keep it reproducible across machines,
+// files of an --inputs list get the command that renders just them
+fn get_command(args: &Args, input: &str, output: &str) -> String {
+    let mut command = Vec::new();
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--inputs" => {
+                arguments.next();
+            }
+            argument if argument.starts_with("--inputs=") => (),
+            _ => command.push(argument),
+        }
+    }
+    if !args.inputs.is_empty() {
+        command.extend([input.to_string(), output.to_string()]);
+    }
+    command.join(" ")
+}
+
+// writes every glyph as its own event on layer 0 that is shown from the start for ten
+// hours, positioned by its top left corner in a script the size of the image. The font is
+// referenced by the file name of --font, which has to match the installed font name
+fn get_ass_subtitles(
+    glyphs: &[(i32, i32, char, Rgba<u8>)],
+    width: u32,
+    height: u32,
+    font: &str,
+    font_size: f32,
+) -> String {
+    let font_name = Path::new(font)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let mut subtitles = format!(
+        "[Script Info]\nScriptType: v4.00+\nPlayResX: {width}\nPlayResY: {height}\nWrapStyle: 2\n\n\
+         [V4+ Styles]\nFormat: Name, Fontname, Fontsize, PrimaryColour, BorderStyle, Outline, Shadow, Alignment\n\
+         Style: Default,{font_name},{font_size},&H00FFFFFF,1,0,0,7\n\n\
+         [Events]\nFormat: Layer, Start, End, Style, Text\n"
+    );
+    for (x, y, glyph, color) in glyphs {
+        // braces and backslashes would start override tags
+        let text = match glyph {
+            '{' | '}' | '\\' => format!("\\{}", glyph),
+            glyph => glyph.to_string(),
+        };
+        // colors are written as blue, green, red with an inverted alpha
+        subtitles.push_str(&format!(
+            "Dialogue: 0,0:00:00.00,9:59:59.99,Default,{{\\pos({x},{y})\\1c&H{:02X}{:02X}{:02X}&\\1a&H{:02X}&}}{text}\n",
+            color[2],
+            color[1],
+            color[0],
+            255 - color[3],
+        ));
+    }
+    subtitles
+}
+
+// formats gauges in the prometheus text exposition format
+fn get_metrics(gauges: &[(&str, &str, f64)]) -> String {
+    gauges
+        .iter()
+        .map(|(name, help, value)| {
+            format!("# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}\n")
+        })
+        .collect()
+}
+
+// collections (.ttc) hold several faces, which are selected by their index
+fn get_font(filename: &str, index: u32) -> Result<FontVec> {
     let mut file = File::open(filename)?;
     let mut data = Vec::new();
     file.read_to_end(&mut data)?;
-    Ok(FontVec::try_from_vec(data)?)
+
+    let face_count = match data.get(0..12) {
+        Some([b't', b't', b'c', b'f', _, _, _, _, count @ ..]) => {
+            u32::from_be_bytes([count[0], count[1], count[2], count[3]])
+        }
+        _ => 1,
+    };
+    if index >= face_count {
+        anyhow::bail!(
+            "font index {} is out of range, the file contains {} font(s)",
+            index,
+            face_count
+        );
+    }
+
+    Ok(FontVec::try_from_vec_and_index(data, index)?)
+}
+
+// reads the palette indices of an indexed png, or the values of a grayscale one, instead
+// of the colors they stand for
+fn get_template_indices(data: &[u8]) -> Result<GrayImage> {
+    let mut decoder = png::Decoder::new(Cursor::new(data));
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let mut reader = decoder.read_info()?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer)?;
+    let is_indexed = matches!(
+        info.color_type,
+        png::ColorType::Indexed | png::ColorType::Grayscale
+    );
+    if !is_indexed || info.bit_depth == png::BitDepth::Sixteen {
+        bail!("the template has to be an indexed or 8 bit grayscale png");
+    }
+
+    // pixels below 8 bits are packed into the bytes of a line from the highest bit on
+    let bits = info.bit_depth as usize;
+    let pixels_per_byte = 8 / bits;
+    let mask = (u16::MAX >> (16 - bits)) as u8;
+    Ok(GrayImage::from_fn(info.width, info.height, |x, y| {
+        let byte = buffer[y as usize * info.line_size + x as usize / pixels_per_byte];
+        let shift = 8 - bits * (x as usize % pixels_per_byte + 1);
+        Luma([(byte >> shift) & mask])
+    }))
+}
+
+// tries the format guessed from the extension and contents, then every other
+// supported format, since files with stripped or wrong extensions are common
+fn decode_image(data: &[u8], filename: &str) -> Result<DynamicImage> {
+    let mut reader = ImageReader::new(Cursor::new(data));
+    if let Ok(format) = ImageFormat::from_path(filename) {
+        reader.set_format(format);
+    }
+    let reader = reader.with_guessed_format()?;
+    let guessed_format = reader.format();
+
+    let mut tried = Vec::new();
+    if let Some(format) = guessed_format {
+        if let Ok(image) = reader.decode() {
+            return Ok(image);
+        }
+        tried.push(format);
+    }
+
+    for format in ImageFormat::all() {
+        if !format.reading_enabled() || Some(format) == guessed_format {
+            continue;
+        }
+        if let Ok(image) = image::load_from_memory_with_format(data, format) {
+            return Ok(image);
+        }
+        tried.push(format);
+    }
+
+    anyhow::bail!(
+        "tried {}",
+        tried
+            .iter()
+            .map(|format| format!("{:?}", format))
+            .collect::<Vec<String>>()
+            .join(", ")
+    )
+}
+
+fn save_image(image: &RgbaImage, filename: &str, comment: Option<&str>) -> Result<()> {
+    let format = ImageFormat::from_path(filename)?;
+    if !format.writing_enabled() {
+        anyhow::bail!("writing {:?} images is not enabled in this build", format);
+    }
+    if comment.is_some() && format != ImageFormat::Png && format != ImageFormat::Jpeg {
+        warn!("Comments can only be embedded in PNG and JPEG files");
+    }
+
+    write_image(
+        image,
+        BufWriter::new(File::create(filename)?),
+        format,
+        comment,
+    )
+}
+
+// encodes the image in the given format, embedding the comment where the format allows it
+fn write_image(
+    image: &RgbaImage,
+    mut writer: impl Write + Seek,
+    format: ImageFormat,
+    comment: Option<&str>,
+) -> Result<()> {
+    match (format, comment) {
+        (ImageFormat::Png, Some(comment)) => write_png_with_comment(image, &mut writer, comment)?,
+        (ImageFormat::Jpeg, comment) => write_jpeg(image, &mut writer, comment)?,
+        (ImageFormat::Bmp, _) => image.write_with_encoder(BmpEncoder::new(&mut writer))?,
+        (ImageFormat::Tiff, _) => image.write_with_encoder(TiffEncoder::new(&mut writer))?,
+        _ => image.write_to(&mut writer, format)?,
+    }
+    Ok(())
+}
+
+// inserts the suffix before the extension, so out.png becomes out_r.png
+fn get_suffixed_filename(filename: &str, suffix: &str) -> String {
+    let path = Path::new(filename);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let channel_filename = match path.extension() {
+        Some(extension) => format!("{}_{}.{}", stem, suffix, extension.to_string_lossy()),
+        None => format!("{}_{}", stem, suffix),
+    };
+    path.with_file_name(channel_filename)
+        .to_string_lossy()
+        .into_owned()
+}
+
+// stores the comment in a tEXt chunk
+fn write_png_with_comment(image: &RgbaImage, writer: impl Write, comment: &str) -> Result<()> {
+    let mut encoder = png::Encoder::new(writer, image.width(), image.height());
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.add_text_chunk("Comment".to_string(), comment.to_string())?;
+    encoder.write_header()?.write_image_data(image.as_raw())?;
+    Ok(())
+}
+
+// stores the comment in a COM segment at the start of the file
+fn write_jpeg(image: &RgbaImage, mut writer: impl Write, comment: Option<&str>) -> Result<()> {
+    // jpeg has no alpha channel
+    let image = RgbImage::from_fn(image.width(), image.height(), |x, y| {
+        image.get_pixel(x, y).to_rgb()
+    });
+    let mut data = Vec::new();
+    image.write_with_encoder(JpegEncoder::new(&mut data))?;
+
+    if let Some(comment) = comment {
+        let comment = comment.as_bytes();
+        let Ok(length) = u16::try_from(comment.len() + 2) else {
+            anyhow::bail!("comment is too long to embed in a JPEG file");
+        };
+        let mut segment = vec![0xFF, 0xFE];
+        segment.extend(length.to_be_bytes());
+        segment.extend(comment);
+        // JFIF requires its APP0 segment to directly follow the start of image marker
+        let position = match data[2..4] {
+            [0xFF, 0xE0] => 4 + u16::from_be_bytes([data[4], data[5]]) as usize,
+            _ => 2,
+        };
+        data.splice(position..position, segment);
+    }
+
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+// coverage of a rasterized glyph, positioned relative to the cell origin
+struct GlyphMask {
+    mask: GrayImage,
+    left: i32,
+    top: i32,
+}
+
+// rasterizes the glyph with the same placement that draw_text_mut uses
+fn rasterize_glyph(font: &FontVec, font_size: f32, glyph: char) -> Option<GlyphMask> {
+    let scaled_font = font.as_scaled(font_size);
+    let glyph = font
+        .glyph_id(glyph)
+        .with_scale_and_position(font_size, point(0.0, scaled_font.ascent()));
+    let outline = font.outline_glyph(glyph)?;
+    let bounds = outline.px_bounds();
+
+    let mut mask = GrayImage::new(bounds.width() as u32, bounds.height() as u32);
+    outline.draw(|gx, gy, gv| {
+        let value = (gv.clamp(0.0, 1.0) * 255.0).round() as u8;
+        mask.put_pixel(gx, gy, Luma([value]));
+    });
+
+    Some(GlyphMask {
+        mask,
+        left: bounds.min.x.round() as i32,
+        top: bounds.min.y.round() as i32,
+    })
+}
+
+fn get_glyph_mask<'a>(
+    glyph_masks: &'a mut HashMap<char, Option<GlyphMask>>,
+    font: &FontVec,
+    font_size: f32,
+    antialias: u32,
+    glyph: char,
+) -> Option<&'a GlyphMask> {
+    glyph_masks
+        .entry(glyph)
+        .or_insert_with(|| {
+            rasterize_glyph(font, font_size * antialias as f32, glyph)
+                .map(|glyph_mask| downsample_glyph_mask(&glyph_mask, antialias))
+        })
+        .as_ref()
+}
+
+// picks the candidate whose coverage differs the least from the cell thresholded
+// at its mean luminance
+fn get_shape_matched_glyph(
+    luma_image: &GrayImage,
+    x: u32,
+    y: u32,
+    glyph_height: u32,
+    candidates: &[(char, u32)],
+    glyph_masks: &HashMap<char, Option<GlyphMask>>,
+    ink_is_light: bool,
+) -> char {
+    let mut best_glyph = candidates[0].0;
+    let mut best_difference = f32::INFINITY;
+
+    for (glyph, glyph_width) in candidates {
+        let section = imageops::crop_imm(luma_image, x, y, *glyph_width, glyph_height);
+        let pixel_amount = section.width() * section.height();
+        if pixel_amount == 0 {
+            continue;
+        }
+        let mean = section
+            .pixels()
+            .map(|(_, _, pixel)| pixel[0] as f32)
+            .sum::<f32>()
+            / pixel_amount as f32;
+
+        let glyph_mask = glyph_masks
+            .get(glyph)
+            .and_then(|glyph_mask| glyph_mask.as_ref());
+        let mut difference = 0.0;
+        for (section_x, section_y, pixel) in section.pixels() {
+            let is_ink = match ink_is_light {
+                true => pixel[0] as f32 > mean,
+                false => (pixel[0] as f32) < mean,
+            };
+            let coverage = glyph_mask
+                .and_then(|glyph_mask| {
+                    let mask_x = u32::try_from(section_x as i32 - glyph_mask.left).ok()?;
+                    let mask_y = u32::try_from(section_y as i32 - glyph_mask.top).ok()?;
+                    glyph_mask.mask.get_pixel_checked(mask_x, mask_y)
+                })
+                .map_or(0.0, |value| value[0] as f32 / 255.0);
+            difference += (coverage - is_ink as u8 as f32).abs();
+        }
+
+        let difference = difference / pixel_amount as f32;
+        if difference < best_difference {
+            best_difference = difference;
+            best_glyph = *glyph;
+        }
+    }
+
+    best_glyph
+}
+
+// averages blocks of factor x factor mask pixels, for glyphs rasterized at a
+// multiple of the font size
+fn downsample_glyph_mask(glyph_mask: &GlyphMask, factor: u32) -> GlyphMask {
+    let factor = factor as i32;
+    let left = glyph_mask.left.div_euclid(factor);
+    let top = glyph_mask.top.div_euclid(factor);
+    let right = (glyph_mask.left + glyph_mask.mask.width() as i32 + factor - 1).div_euclid(factor);
+    let bottom = (glyph_mask.top + glyph_mask.mask.height() as i32 + factor - 1).div_euclid(factor);
+
+    let width = (right - left) as u32;
+    let height = (bottom - top) as u32;
+    let mut coverage = vec![0.0; (width * height) as usize];
+    for (mask_x, mask_y, mask_value) in glyph_mask.mask.enumerate_pixels() {
+        let x = (glyph_mask.left + mask_x as i32).div_euclid(factor) - left;
+        let y = (glyph_mask.top + mask_y as i32).div_euclid(factor) - top;
+        coverage[(y as u32 * width + x as u32) as usize] += mask_value[0] as f32;
+    }
+
+    let samples = (factor * factor) as f32;
+    let mask = GrayImage::from_fn(width, height, |x, y| {
+        Luma([(coverage[(y * width + x) as usize] / samples).round() as u8])
+    });
+
+    GlyphMask { mask, left, top }
+}
+
+// flips the mask within the advance of the glyph so that it stays inside its cell
+fn get_mirrored_glyph_mask(glyph_mask: &GlyphMask, glyph_width: f32) -> GlyphMask {
+    GlyphMask {
+        mask: imageops::flip_horizontal(&glyph_mask.mask),
+        left: glyph_width.round() as i32 - glyph_mask.left - glyph_mask.mask.width() as i32,
+        top: glyph_mask.top,
+    }
 }
 
-fn get_average_color(image_section: DynamicImage) -> Rgba<u8> {
+// pads the mask to its diagonal so that no corner is cut off by the rotation
+fn get_rotated_glyph_mask(glyph_mask: &GlyphMask, degrees: f32) -> GlyphMask {
+    let (width, height) = glyph_mask.mask.dimensions();
+    let size = ((width * width + height * height) as f32).sqrt().ceil() as u32;
+    let padding_x = (size - width) / 2;
+    let padding_y = (size - height) / 2;
+
+    let mut padded = GrayImage::new(size, size);
+    imageops::replace(
+        &mut padded,
+        &glyph_mask.mask,
+        padding_x as i64,
+        padding_y as i64,
+    );
+    let mask = rotate_about_center(
+        &padded,
+        degrees.to_radians(),
+        Interpolation::Bilinear,
+        Luma([0]),
+    );
+
+    GlyphMask {
+        mask,
+        left: glyph_mask.left - padding_x as i32,
+        top: glyph_mask.top - padding_y as i32,
+    }
+}
+
+// yields the image positions inside width and height that the mask placed at x, y
+// covers, with their coverage from 0 to 1
+fn get_covered_pixels(
+    glyph_mask: &GlyphMask,
+    x: i32,
+    y: i32,
+    width: u32,
+    height: u32,
+) -> impl Iterator<Item = (u32, u32, f32)> + '_ {
+    glyph_mask
+        .mask
+        .enumerate_pixels()
+        .filter(|(_, _, mask_value)| mask_value[0] > 0)
+        .filter_map(move |(mask_x, mask_y, mask_value)| {
+            let px = u32::try_from(x + glyph_mask.left + mask_x as i32).ok()?;
+            let py = u32::try_from(y + glyph_mask.top + mask_y as i32).ok()?;
+            (px < width && py < height).then(|| (px, py, mask_value[0] as f32 / 255.0))
+        })
+}
+
+// blends the color into the image wherever the mask has coverage
+fn draw_glyph_mask(
+    output_image: &mut RgbaImage,
+    glyph_mask: &GlyphMask,
+    x: i32,
+    y: i32,
+    color: Rgba<u8>,
+    opacity: f32,
+) {
+    let (width, height) = output_image.dimensions();
+    for (px, py, coverage) in get_covered_pixels(glyph_mask, x, y, width, height) {
+        let gv = coverage * opacity;
+        let pixel = output_image.get_pixel_mut(px, py);
+        *pixel = weighted_sum(*pixel, color, 1.0 - gv, gv);
+    }
+}
+
+// blends the source pixel at each position instead of a flat color, so the glyph
+// shows the image through it
+fn draw_textured_glyph_mask(
+    output_image: &mut RgbaImage,
+    glyph_mask: &GlyphMask,
+    x: i32,
+    y: i32,
+    texture: &DynamicImage,
+    opacity: f32,
+) {
+    let (width, height) = output_image.dimensions();
+    for (px, py, coverage) in get_covered_pixels(glyph_mask, x, y, width, height) {
+        let gv = coverage * opacity;
+        let color = texture.get_pixel(px, py);
+        let pixel = output_image.get_pixel_mut(px, py);
+        *pixel = weighted_sum(*pixel, color, 1.0 - gv, gv);
+    }
+}
+
+// blends a single color channel, so offset channels add up instead of covering
+// each other
+fn draw_glyph_mask_channel(
+    output_image: &mut RgbaImage,
+    glyph_mask: &GlyphMask,
+    x: i32,
+    y: i32,
+    color: Rgba<u8>,
+    channel: usize,
+    opacity: f32,
+) {
+    let (width, height) = output_image.dimensions();
+    for (px, py, coverage) in get_covered_pixels(glyph_mask, x, y, width, height) {
+        let gv = coverage * opacity;
+        let pixel = output_image.get_pixel_mut(px, py);
+        for index in [channel, 3] {
+            pixel[index] = (pixel[index] as f32 * (1.0 - gv) + color[index] as f32 * gv) as u8;
+        }
+    }
+}
+
+// only every sample_step-th pixel in each direction is taken into account
+fn get_average_color(
+    image_section: &DynamicImage,
+    sample_step: u32,
+    legacy_average: bool,
+) -> Rgba<u8> {
     let mut r: usize = 0;
     let mut g: usize = 0;
     let mut b: usize = 0;
     let mut a: usize = 0;
+    let mut pixel_amount: usize = 0;
 
-    for pixel in image_section.pixels() {
-        let color = pixel.2;
+    for y in (0..image_section.height()).step_by(sample_step as usize) {
+        for x in (0..image_section.width()).step_by(sample_step as usize) {
+            let color = image_section.get_pixel(x, y);
 
-        r += color[0] as usize;
-        g += color[1] as usize;
-        b += color[2] as usize;
-        a += color[3] as usize;
+            r += color[0] as usize;
+            g += color[1] as usize;
+            b += color[2] as usize;
+            a += color[3] as usize;
+            pixel_amount += 1;
+        }
     }
 
-    let pixel_amount = image_section.pixels().count();
-
-    r /= pixel_amount;
-    g /= pixel_amount;
-    b /= pixel_amount;
-    a /= pixel_amount;
+    // rounding to nearest avoids darkening every cell slightly, truncation is kept for
+    // reproducing older renders
+    let rounding = match legacy_average {
+        true => 0,
+        false => pixel_amount / 2,
+    };
+    r = (r + rounding) / pixel_amount;
+    g = (g + rounding) / pixel_amount;
+    b = (b + rounding) / pixel_amount;
+    a = (a + rounding) / pixel_amount;
 
     Rgba::from([r as u8, g as u8, b as u8, a as u8])
 }
 
+// weights the pixels with a gaussian centered on the cell and reaches half a cell into
+// the neighbors, so colors blend across cell boundaries
+fn get_soft_average_color(
+    image: &DynamicImage,
+    left: u32,
+    top: u32,
+    width: u32,
+    height: u32,
+) -> Rgba<u8> {
+    let center_x = left as f32 + width as f32 / 2.0;
+    let center_y = top as f32 + height as f32 / 2.0;
+    let sigma_x = (width as f32 / 2.0).max(0.5);
+    let sigma_y = (height as f32 / 2.0).max(0.5);
+
+    let start_x = left.saturating_sub(width / 2);
+    let start_y = top.saturating_sub(height / 2);
+    let end_x = (left + width + width / 2).min(image.width());
+    let end_y = (top + height + height / 2).min(image.height());
+
+    let mut sums = [0.0; 4];
+    let mut total_weight = 0.0;
+    for y in start_y..end_y {
+        for x in start_x..end_x {
+            let dx = (x as f32 + 0.5 - center_x) / sigma_x;
+            let dy = (y as f32 + 0.5 - center_y) / sigma_y;
+            let weight = (-(dx * dx + dy * dy) / 2.0).exp();
+            let color = image.get_pixel(x, y);
+            for (sum, value) in sums.iter_mut().zip(color.0) {
+                *sum += value as f32 * weight;
+            }
+            total_weight += weight;
+        }
+    }
+
+    let [r, g, b, a] = sums.map(|sum| (sum / total_weight).round() as u8);
+    Rgba::from([r, g, b, a])
+}
+
+// averages only the pixels covered by the glyph, weighted by coverage
+fn get_ink_average_color(image_section: &DynamicImage, glyph_mask: &GlyphMask) -> Option<Rgba<u8>> {
+    let mut r = 0.0;
+    let mut g = 0.0;
+    let mut b = 0.0;
+    let mut a = 0.0;
+    let mut coverage = 0.0;
+
+    for (mask_x, mask_y, mask_value) in glyph_mask.mask.enumerate_pixels() {
+        let px = mask_x as i32 + glyph_mask.left;
+        let py = mask_y as i32 + glyph_mask.top;
+        if px < 0 || py < 0 || !image_section.in_bounds(px as u32, py as u32) {
+            continue;
+        }
+        let gv = mask_value[0] as f32 / 255.0;
+        let color = image_section.get_pixel(px as u32, py as u32);
+
+        r += color[0] as f32 * gv;
+        g += color[1] as f32 * gv;
+        b += color[2] as f32 * gv;
+        a += color[3] as f32 * gv;
+        coverage += gv;
+    }
+
+    if coverage == 0.0 {
+        return None;
+    }
+
+    Some(Rgba::from([
+        (r / coverage).round() as u8,
+        (g / coverage).round() as u8,
+        (b / coverage).round() as u8,
+        (a / coverage).round() as u8,
+    ]))
+}
+
 fn get_rgba_from_hex(hex: &str) -> Result<Rgba<u8>> {
     let hex = hex.replace("#", "");
 
@@ -249,6 +2204,256 @@ fn get_rgba_from_hex(hex: &str) -> Result<Rgba<u8>> {
     Ok(Rgba::from([r, g, b, 255]))
 }
 
+// parses an offset like "2,-1" into x and y
+fn get_shadow_offset(offset: &str) -> Result<(i32, i32)> {
+    let (x, y) = offset
+        .split_once(',')
+        .ok_or_else(|| anyhow::anyhow!("expected an offset like 2,2"))?;
+    Ok((x.trim().parse::<i32>()?, y.trim().parse::<i32>()?))
+}
+
+// parses dimensions like "3x2" into a width and a height
+fn get_dimensions(dimensions: &str) -> Result<(u32, u32)> {
+    let (width, height) = dimensions
+        .to_lowercase()
+        .split_once('x')
+        .map(|(width, height)| (width.trim().parse::<u32>(), height.trim().parse::<u32>()))
+        .ok_or_else(|| anyhow::anyhow!("expected dimensions like 3x2"))?;
+    let (width, height) = (width?, height?);
+    if width == 0 || height == 0 {
+        anyhow::bail!("dimensions must be at least 1");
+    }
+    Ok((width, height))
+}
+
+fn get_tiled_image(image: &DynamicImage, columns: u32, rows: u32) -> Option<DynamicImage> {
+    let tile = image.to_rgba8();
+    let width = tile.width().checked_mul(columns)?;
+    let height = tile.height().checked_mul(rows)?;
+    let tiled = RgbaImage::from_fn(width, height, |x, y| {
+        *tile.get_pixel(x % tile.width(), y % tile.height())
+    });
+    Some(DynamicImage::ImageRgba8(tiled))
+}
+
+// the lowest and highest values once the cutoff amount is skipped on both ends
+fn get_histogram_bounds(histogram: &[usize; 256], cutoff: usize) -> (usize, usize) {
+    let mut count = 0;
+    let low = histogram
+        .iter()
+        .position(|amount| {
+            count += amount;
+            count > cutoff
+        })
+        .unwrap_or(0);
+    count = 0;
+    let high = 255
+        - histogram
+            .iter()
+            .rev()
+            .position(|amount| {
+                count += amount;
+                count > cutoff
+            })
+            .unwrap_or(0);
+    (low, high)
+}
+
+// stretches the luminance range of the image to the full range, ignoring the
+// darkest and brightest percent of pixels
+fn get_contrast_stretched_image(image: &DynamicImage) -> DynamicImage {
+    let mut image = image.to_rgba8();
+
+    let mut histogram = [0usize; 256];
+    for pixel in image.pixels() {
+        histogram[get_luminance(pixel).round() as usize] += 1;
+    }
+
+    let (low, high) = get_histogram_bounds(&histogram, image.pixels().len() / 100);
+
+    if high <= low {
+        return DynamicImage::ImageRgba8(image);
+    }
+
+    let factor = 255.0 / (high - low) as f32;
+    for pixel in image.pixels_mut() {
+        for channel in 0..3 {
+            let value = (pixel[channel] as f32 - low as f32) * factor;
+            pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    DynamicImage::ImageRgba8(image)
+}
+
+// sorts each run of pixels brighter than the threshold along the row by luminance
+fn get_pixel_sorted_image(image: &DynamicImage, threshold: f32) -> DynamicImage {
+    let mut image = image.to_rgba8();
+    let width = image.width() as usize;
+    for row in image.chunks_exact_mut((width * 4).max(1)) {
+        let mut pixels: Vec<Rgba<u8>> = row
+            .chunks_exact(4)
+            .map(|pixel| Rgba::from([pixel[0], pixel[1], pixel[2], pixel[3]]))
+            .collect();
+        for run in pixels.split_mut(|pixel| get_luminance(pixel) <= threshold) {
+            run.sort_by(|a, b| get_luminance(a).total_cmp(&get_luminance(b)));
+        }
+        for (target, pixel) in row.chunks_exact_mut(4).zip(pixels) {
+            target.copy_from_slice(&pixel.0);
+        }
+    }
+    DynamicImage::ImageRgba8(image)
+}
+
+// auto stretches every channel to the full range on its own, and gray-world scales the
+// channels so that their averages match
+fn get_white_balanced_image(image: &DynamicImage, white_balance: &str) -> DynamicImage {
+    let mut image = image.to_rgba8();
+
+    let factors_and_offsets: [(f32, f32); 3] = match white_balance.to_lowercase().as_str() {
+        "auto" => [0, 1, 2].map(|channel| {
+            let mut histogram = [0usize; 256];
+            for pixel in image.pixels() {
+                histogram[pixel[channel] as usize] += 1;
+            }
+            let (low, high) = get_histogram_bounds(&histogram, image.pixels().len() / 100);
+            match high > low {
+                true => (255.0 / (high - low) as f32, low as f32),
+                false => (1.0, 0.0),
+            }
+        }),
+        "gray-world" => {
+            let mut sums = [0.0f64; 3];
+            for pixel in image.pixels() {
+                for (sum, value) in sums.iter_mut().zip(pixel.0) {
+                    *sum += value as f64;
+                }
+            }
+            let gray = sums.iter().sum::<f64>() / 3.0;
+            sums.map(|sum| match sum > 0.0 {
+                true => ((gray / sum) as f32, 0.0),
+                false => (1.0, 0.0),
+            })
+        }
+        _ => return DynamicImage::ImageRgba8(image),
+    };
+
+    for pixel in image.pixels_mut() {
+        for (channel, (factor, offset)) in factors_and_offsets.iter().enumerate() {
+            let value = (pixel[channel] as f32 - offset) * factor;
+            pixel[channel] = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    DynamicImage::ImageRgba8(image)
+}
+
+fn get_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    }
+}
+
+// adds the same random offset to each color channel of a pixel
+fn add_grain(image: &mut RgbaImage, amount: u8, rng: &mut StdRng) {
+    let amount = amount as i16;
+    for pixel in image.pixels_mut() {
+        let noise = rng.gen_range(-amount..=amount);
+        for channel in 0..3 {
+            pixel[channel] = (pixel[channel] as i16 + noise).clamp(0, 255) as u8;
+        }
+    }
+}
+
+// the largest centered rectangle with the given aspect ratio
+fn get_safe_area(ratio: &str, width: u32, height: u32) -> Option<Rect> {
+    let (ratio_width, ratio_height) = match ratio {
+        "1:1" => (1, 1),
+        "4:5" => (4, 5),
+        "9:16" => (9, 16),
+        _ => return None,
+    };
+    let (area_width, area_height) = match width * ratio_height > height * ratio_width {
+        true => (height * ratio_width / ratio_height, height),
+        false => (width, width * ratio_height / ratio_width),
+    };
+    Some(
+        Rect::at(
+            ((width - area_width) / 2) as i32,
+            ((height - area_height) / 2) as i32,
+        )
+        .of_size(area_width.max(1), area_height.max(1)),
+    )
+}
+
+fn get_filter_type(name: &str) -> Option<FilterType> {
+    match name.to_lowercase().as_str() {
+        "nearest" => Some(FilterType::Nearest),
+        "triangle" => Some(FilterType::Triangle),
+        "catmullrom" => Some(FilterType::CatmullRom),
+        "gaussian" => Some(FilterType::Gaussian),
+        "lanczos3" => Some(FilterType::Lanczos3),
+        _ => None,
+    }
+}
+
+fn get_luminance(color: &Rgba<u8>) -> f32 {
+    0.299 * color[0] as f32 + 0.587 * color[1] as f32 + 0.114 * color[2] as f32
+}
+
+fn draw_signature(
+    output_image: &mut RgbaImage,
+    font: &FontVec,
+    font_size: f32,
+    signature: &str,
+    position: SignaturePosition,
+    color: Option<Rgba<u8>>,
+) {
+    let (text_width, _) = text_size(font_size, font, signature);
+    let text_height = font.as_scaled(font_size).height().ceil() as u32;
+    let margin = (font_size / 2.0).round() as u32;
+
+    let width = output_image.width();
+    let height = output_image.height();
+    let x = match position {
+        SignaturePosition::TopLeft | SignaturePosition::BottomLeft => margin,
+        SignaturePosition::TopRight | SignaturePosition::BottomRight => {
+            width.saturating_sub(text_width + margin)
+        }
+    };
+    let y = match position {
+        SignaturePosition::TopLeft | SignaturePosition::TopRight => margin,
+        SignaturePosition::BottomLeft | SignaturePosition::BottomRight => {
+            height.saturating_sub(text_height + margin)
+        }
+    };
+
+    // pick black or white depending on what is already drawn beneath the signature
+    let color = color.unwrap_or_else(|| {
+        let section = DynamicImage::ImageRgba8(
+            imageops::crop_imm(output_image, x, y, text_width, text_height).to_image(),
+        );
+        let is_light = section.width() > 0
+            && section.height() > 0
+            && get_luminance(&get_average_color(&section, 1, false)) > 127.0;
+        match is_light {
+            true => Rgba::from([0, 0, 0, 255]),
+            false => Rgba::from([255, 255, 255, 255]),
+        }
+    });
+
+    draw_text_mut(
+        output_image,
+        color,
+        x as i32,
+        y as i32,
+        font_size,
+        font,
+        signature,
+    );
+}
+
 fn sanatize_text(text: String) -> String {
     text.replace(|c: char| !c.is_alphabetic(), "")
 }
@@ -269,6 +2474,9 @@ enum Charset {
     Hexadecimal,
     Braille,
     PlayingCards,
+    Tifinagh,
+    Cherokee,
+    Font,
 }
 
 impl FromStr for Charset {
@@ -292,37 +2500,203 @@ impl FromStr for Charset {
             "binary" => Ok(Self::Binary),
             "braille" => Ok(Self::Braille),
             "playingcards" => Ok(Self::PlayingCards),
+            "tifinagh" => Ok(Self::Tifinagh),
+            "cherokee" => Ok(Self::Cherokee),
+            "font" => Ok(Self::Font),
             _ => Err(()),
         }
     }
 }
 
-fn get_characters(charset: Charset) -> Vec<char> {
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Normal,
+    Outline,
+    ShapeMatch,
+    Pixelate,
+}
+
+impl FromStr for Mode {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        match s.as_str() {
+            "normal" => Ok(Self::Normal),
+            "outline" => Ok(Self::Outline),
+            "shape-match" => Ok(Self::ShapeMatch),
+            "pixelate" => Ok(Self::Pixelate),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Image,
+    Ass,
+}
+
+impl FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        match s.as_str() {
+            "image" => Ok(Self::Image),
+            "ass" => Ok(Self::Ass),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Layout {
+    Float,
+    Integer,
+}
+
+impl FromStr for Layout {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        match s.as_str() {
+            "float" => Ok(Self::Float),
+            "integer" => Ok(Self::Integer),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SignaturePosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for SignaturePosition {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        match s.as_str() {
+            "top-left" => Ok(Self::TopLeft),
+            "top-right" => Ok(Self::TopRight),
+            "bottom-left" => Ok(Self::BottomLeft),
+            "bottom-right" => Ok(Self::BottomRight),
+            _ => Err(()),
+        }
+    }
+}
+
+fn get_charset_names() -> Vec<String> {
+    let command = Args::command();
+    let charset_arg = command
+        .get_arguments()
+        .find(|arg| arg.get_id() == "charset")
+        .expect("charset is a defined argument");
+
+    charset_arg
+        .get_possible_values()
+        .iter()
+        .map(|name| name.get_name().to_string())
+        .collect()
+}
+
+fn list_charsets(include_non_alpha: bool) {
+    for name in get_charset_names() {
+        let charset = Charset::from_str(&name).expect("possible values are valid charsets");
+        // the font charset depends on the font, which isn't loaded here
+        let sample: String = match charset {
+            Charset::Font => String::from("(characters of --font)"),
+            charset => get_characters(charset, include_non_alpha, None)
+                .into_iter()
+                .take(16)
+                .collect(),
+        };
+        println!("{:<14}{}", name, sample);
+    }
+}
+
+// renders one labeled row per charset, so missing glyphs in the font show up as tofu or gaps
+fn get_charset_sheet(font: &FontVec, font_size: f32, include_non_alpha: bool) -> RgbaImage {
+    const SAMPLE_LENGTH: usize = 32;
+
+    let rows: Vec<(String, String)> = get_charset_names()
+        .into_iter()
+        .map(|name| {
+            let charset = Charset::from_str(&name).expect("possible values are valid charsets");
+            let sample: String = get_characters(charset, include_non_alpha, Some(font))
+                .into_iter()
+                .take(SAMPLE_LENGTH)
+                .flat_map(|c| [c, ' '])
+                .collect();
+            (name, sample)
+        })
+        .collect();
+
+    let margin = (font_size / 2.0).ceil() as u32;
+    let row_height = font.as_scaled(font_size).height().ceil() as u32 + margin;
+    let label_width = rows
+        .iter()
+        .map(|(name, _)| text_size(font_size, font, name).0)
+        .max()
+        .unwrap_or(0)
+        + 2 * margin;
+    let sample_width = rows
+        .iter()
+        .map(|(_, sample)| text_size(font_size, font, sample).0)
+        .max()
+        .unwrap_or(0);
+
+    let mut sheet = RgbaImage::from_pixel(
+        label_width + sample_width + margin,
+        row_height * rows.len() as u32 + margin,
+        Rgba::from([255, 255, 255, 255]),
+    );
+    for (i, (name, sample)) in rows.iter().enumerate() {
+        let y = (margin + i as u32 * row_height) as i32;
+        let label_color = Rgba::from([128, 128, 128, 255]);
+        draw_text_mut(
+            &mut sheet,
+            label_color,
+            margin as i32,
+            y,
+            font_size,
+            font,
+            name,
+        );
+        let sample_color = Rgba::from([0, 0, 0, 255]);
+        draw_text_mut(
+            &mut sheet,
+            sample_color,
+            label_width as i32,
+            y,
+            font_size,
+            font,
+            sample,
+        );
+    }
+
+    sheet
+}
+
+// the font charset needs the loaded font and is empty without one
+fn get_characters(charset: Charset, include_non_alpha: bool, font: Option<&FontVec>) -> Vec<char> {
+    let is_included = |c: &char| include_non_alpha || c.is_alphabetic();
+
     match charset {
-        Charset::Latin => ('\u{0041}'..='\u{007A}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
-        Charset::Cyrillic => ('\u{0400}'..='\u{04FF}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
-        Charset::Runic => ('\u{16A0}'..='\u{16FF}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
-        Charset::Hebrew => ('\u{0590}'..='\u{05FF}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
-        Charset::Hiragana => ('\u{3040}'..='\u{309F}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
-        Charset::Hangul => ('\u{1100}'..='\u{11FF}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
-        Charset::Katakana => ('\u{30A0}'..='\u{30FF}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
-        Charset::CkjUnified => ('\u{4E00}'..='\u{9FFF}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
+        Charset::Latin => ('\u{0041}'..='\u{007A}').filter(is_included).collect(),
+        Charset::Cyrillic => ('\u{0400}'..='\u{04FF}').filter(is_included).collect(),
+        Charset::Runic => ('\u{16A0}'..='\u{16FF}').filter(is_included).collect(),
+        Charset::Hebrew => ('\u{0590}'..='\u{05FF}').filter(is_included).collect(),
+        Charset::Hiragana => ('\u{3040}'..='\u{309F}').filter(is_included).collect(),
+        Charset::Hangul => ('\u{1100}'..='\u{11FF}').filter(is_included).collect(),
+        Charset::Katakana => ('\u{30A0}'..='\u{30FF}').filter(is_included).collect(),
+        Charset::CkjUnified => ('\u{4E00}'..='\u{9FFF}').filter(is_included).collect(),
         Charset::Emoticons => ('\u{1F600}'..='\u{1F64F}').collect(),
         Charset::Decimal => ('0'..='9').collect(),
         Charset::Hexadecimal => {
@@ -332,15 +2706,110 @@ fn get_characters(charset: Charset) -> Vec<char> {
         }
         Charset::Binary => vec!['0', '1'],
         Charset::Braille => ('\u{2800}'..='\u{28FF}').collect(),
-        Charset::Greek => ('\u{0370}'..='\u{03E1}')
-            .filter(|c| c.is_alphabetic())
-            .collect(),
+        Charset::Greek => ('\u{0370}'..='\u{03E1}').filter(is_included).collect(),
         Charset::PlayingCards => ('\u{1F0A0}'..='\u{1F0DF}')
             .filter(|c| {
                 *c != '\u{1F0AF}' && *c != '\u{1F0B0}' && *c != '\u{1F0C0}' && *c != '\u{1F0D0}'
             })
             .collect(),
+        Charset::Tifinagh => ('\u{2D30}'..='\u{2D7F}').filter(is_included).collect(),
+        Charset::Cherokee => ('\u{13A0}'..='\u{13FF}').filter(is_included).collect(),
+        Charset::Font => {
+            let mut characters: Vec<char> = font
+                .map(|font| {
+                    font.codepoint_ids()
+                        .map(|(_, c)| c)
+                        .filter(is_included)
+                        .collect()
+                })
+                .unwrap_or_default();
+            characters.sort_unstable();
+            characters.dedup();
+            characters
+        }
+    }
+}
+
+// parses comma separated hex codepoint ranges like "4E00-9FFF,3040-309F"
+fn get_range_characters(ranges: &str, include_non_alpha: bool) -> Result<Vec<char>> {
+    let mut characters = Vec::new();
+    for range in ranges.split(',') {
+        let (start, end) = range
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| anyhow::anyhow!("expected a range like 4E00-9FFF"))?;
+        let start = u32::from_str_radix(start.trim(), 16)?;
+        let end = u32::from_str_radix(end.trim(), 16)?;
+        if start > end {
+            anyhow::bail!("range start {:X} is after its end {:X}", start, end);
+        }
+        characters.extend(
+            (start..=end)
+                .filter_map(char::from_u32)
+                .filter(|c| include_non_alpha || c.is_alphabetic()),
+        );
+    }
+    if characters.is_empty() {
+        anyhow::bail!("the ranges contain no usable characters");
+    }
+    Ok(characters)
+}
+
+// counts the characters of the corpus, most frequent first
+fn get_character_frequencies(
+    corpus: &str,
+    include_non_alpha: bool,
+    exclude: &str,
+    top_n: Option<u64>,
+    is_drawable: impl Fn(char) -> bool,
+) -> Vec<(char, u64)> {
+    let mut counts: HashMap<char, u64> = HashMap::new();
+    for c in corpus.chars() {
+        if (include_non_alpha || c.is_alphabetic())
+            && !c.is_whitespace()
+            && !exclude.contains(c)
+            && is_drawable(c)
+        {
+            *counts.entry(c).or_default() += 1;
+        }
+    }
+
+    let mut frequencies: Vec<(char, u64)> = counts.into_iter().collect();
+    frequencies.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+    if let Some(top_n) = top_n {
+        frequencies.truncate(top_n as usize);
+    }
+    frequencies
+}
+
+// parses bands like "dark:braille,light:latin" or "0:braille,96:hebrew,192:latin", where
+// each band starts at the given luminance and dark and light stand for 0 and 128
+fn get_charset_map(
+    map: &str,
+    include_non_alpha: bool,
+    font: Option<&FontVec>,
+) -> Result<Vec<(f32, Vec<char>)>> {
+    let mut bands = Vec::new();
+    for band in map.split(',') {
+        let (threshold, charset) = band
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("expected a band like dark:braille"))?;
+        let threshold = match threshold.trim() {
+            "dark" => 0.0,
+            "light" => 128.0,
+            threshold => threshold.parse::<f32>()?,
+        };
+        let charset = Charset::from_str(charset.trim())
+            .map_err(|_| anyhow::anyhow!("unknown charset {}", charset.trim()))?;
+        let characters = get_characters(charset, include_non_alpha, font);
+        if characters.is_empty() {
+            anyhow::bail!("a charset contains no usable characters");
+        }
+        bands.push((threshold, characters));
     }
+    bands.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    Ok(bands)
 }
 
 fn get_dimmed_color(color: &Rgba<u8>) -> Rgba<u8> {
@@ -351,3 +2820,459 @@ fn get_dimmed_color(color: &Rgba<u8>) -> Rgba<u8> {
         color[3],
     ])
 }
+
+fn get_posterized_color(color: &Rgba<u8>, bits: u8) -> Rgba<u8> {
+    let mask = u8::MAX << (8 - bits);
+    Rgba::from([color[0] & mask, color[1] & mask, color[2] & mask, color[3]])
+}
+
+// clusters randomly sampled pixels into at most max_colors colors, starting from
+// randomly picked samples
+fn get_kmeans_palette(image: &DynamicImage, max_colors: u32, rng: &mut StdRng) -> Vec<Rgba<u8>> {
+    const SAMPLES: usize = 10_000;
+    const ITERATIONS: usize = 20;
+
+    let samples: Vec<[f32; 3]> = (0..SAMPLES)
+        .map(|_| {
+            let pixel = image.get_pixel(
+                rng.gen_range(0..image.width()),
+                rng.gen_range(0..image.height()),
+            );
+            [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]
+        })
+        .collect();
+    let mut centroids: Vec<[f32; 3]> = samples
+        .choose_multiple(rng, max_colors as usize)
+        .copied()
+        .collect();
+
+    for _ in 0..ITERATIONS {
+        let mut sums = vec![([0.0; 3], 0usize); centroids.len()];
+        for sample in &samples {
+            let (sum, count) = &mut sums[get_nearest_centroid(sample, &centroids)];
+            for channel in 0..3 {
+                sum[channel] += sample[channel];
+            }
+            *count += 1;
+        }
+        let mut has_moved = false;
+        for (centroid, (sum, count)) in centroids.iter_mut().zip(sums) {
+            // a centroid without samples keeps its position
+            if count == 0 {
+                continue;
+            }
+            let mean = sum.map(|value| value / count as f32);
+            has_moved |= mean != *centroid;
+            *centroid = mean;
+        }
+        if !has_moved {
+            break;
+        }
+    }
+
+    centroids
+        .iter()
+        .map(|centroid| {
+            let [r, g, b] = centroid.map(|value| value.round() as u8);
+            Rgba::from([r, g, b, u8::MAX])
+        })
+        .collect()
+}
+
+fn get_nearest_centroid(sample: &[f32; 3], centroids: &[[f32; 3]]) -> usize {
+    let distance = |centroid: &[f32; 3]| {
+        (0..3)
+            .map(|channel| (sample[channel] - centroid[channel]).powi(2))
+            .sum::<f32>()
+    };
+    (0..centroids.len())
+        .min_by(|&a, &b| distance(&centroids[a]).total_cmp(&distance(&centroids[b])))
+        .expect("palette should never be empty")
+}
+
+// keeps the alpha of the color
+fn get_nearest_palette_color(color: &Rgba<u8>, palette: &[Rgba<u8>]) -> Rgba<u8> {
+    let centroids: Vec<[f32; 3]> = palette
+        .iter()
+        .map(|entry| [entry[0] as f32, entry[1] as f32, entry[2] as f32])
+        .collect();
+    let sample = [color[0] as f32, color[1] as f32, color[2] as f32];
+    let entry = palette[get_nearest_centroid(&sample, &centroids)];
+    Rgba::from([entry[0], entry[1], entry[2], color[3]])
+}
+
+// moves the lightness of the color away from the background until their luminance
+// differs by at least a quarter of the range
+fn get_readable_color(color: &Rgba<u8>, background: &Rgba<u8>) -> Rgba<u8> {
+    const MIN_DIFFERENCE: f32 = 64.0;
+
+    let background_luminance = get_luminance(background);
+    if (get_luminance(color) - background_luminance).abs() >= MIN_DIFFERENCE {
+        return *color;
+    }
+
+    let (hue, saturation, lightness) = rgb_to_hsl(color);
+    let step = match background_luminance > 127.0 {
+        true => -0.05,
+        false => 0.05,
+    };
+    let mut readable_color = *color;
+    for i in 1..=20 {
+        let (r, g, b) = hsl_to_rgb(
+            hue,
+            saturation,
+            (lightness + step * i as f32).clamp(0.0, 1.0),
+        );
+        readable_color = Rgba::from([r, g, b, color[3]]);
+        if (get_luminance(&readable_color) - background_luminance).abs() >= MIN_DIFFERENCE {
+            break;
+        }
+    }
+    readable_color
+}
+
+// keeps the hue of the color but replaces its saturation and lightness
+fn get_hue_shifted_color(color: &Rgba<u8>, saturation: f32, lightness: f32) -> Rgba<u8> {
+    let (hue, _, _) = rgb_to_hsl(color);
+    let (r, g, b) = hsl_to_rgb(hue, saturation.clamp(0.0, 1.0), lightness.clamp(0.0, 1.0));
+    Rgba::from([r, g, b, color[3]])
+}
+
+fn rgb_to_hsl(color: &Rgba<u8>) -> (f32, f32, f32) {
+    let r = color[0] as f32 / 255.0;
+    let g = color[1] as f32 / 255.0;
+    let b = color[2] as f32 / 255.0;
+
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let lightness = (max + min) / 2.0;
+    let delta = max - min;
+
+    if delta == 0.0 {
+        return (0.0, 0.0, lightness);
+    }
+
+    let saturation = delta / (1.0 - (2.0 * lightness - 1.0).abs());
+    let hue = if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+
+    (hue, saturation, lightness)
+}
+
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> (u8, u8, u8) {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0).rem_euclid(2.0) - 1.0).abs());
+    let m = lightness - chroma / 2.0;
+
+    let (r, g, b) = match hue {
+        h if h < 60.0 => (chroma, x, 0.0),
+        h if h < 120.0 => (x, chroma, 0.0),
+        h if h < 180.0 => (0.0, chroma, x),
+        h if h < 240.0 => (0.0, x, chroma),
+        h if h < 300.0 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    (
+        ((r + m) * 255.0).round() as u8,
+        ((g + m) * 255.0).round() as u8,
+        ((b + m) * 255.0).round() as u8,
+    )
+}
+
+fn get_isolated_color(color: &Rgba<u8>, channel: &str) -> Rgba<u8> {
+    match channel.to_lowercase().as_str() {
+        "r" => Rgba::from([color[0], 0, 0, color[3]]),
+        "g" => Rgba::from([0, color[1], 0, color[3]]),
+        "b" => Rgba::from([0, 0, color[2], color[3]]),
+        _ => *color,
+    }
+}
+
+// simulates a color vision deficiency, or with safe set shifts the colors the deficiency
+// loses into channels that are still distinguishable
+fn get_cvd_color(color: &Rgba<u8>, cvd: &str, safe: bool) -> Rgba<u8> {
+    let matrix = match cvd.to_lowercase().as_str() {
+        "protanopia" => [
+            [0.152286, 1.052583, -0.204868],
+            [0.114503, 0.786281, 0.099216],
+            [-0.003882, -0.048116, 1.051998],
+        ],
+        "deuteranopia" => [
+            [0.367322, 0.860646, -0.227968],
+            [0.280085, 0.672501, 0.047413],
+            [-0.011820, 0.042940, 0.968881],
+        ],
+        "tritanopia" => [
+            [1.255528, -0.076749, -0.178779],
+            [-0.078411, 0.930809, 0.147602],
+            [0.004733, 0.691367, 0.303900],
+        ],
+        _ => return *color,
+    };
+
+    let rgb = [color[0] as f32, color[1] as f32, color[2] as f32];
+    let simulated = matrix.map(|row| row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2]);
+    let adjusted = match safe {
+        true => {
+            let error = [
+                rgb[0] - simulated[0],
+                rgb[1] - simulated[1],
+                rgb[2] - simulated[2],
+            ];
+            [
+                rgb[0],
+                rgb[1] + 0.7 * error[0] + error[1],
+                rgb[2] + 0.7 * error[0] + error[2],
+            ]
+        }
+        false => simulated,
+    };
+
+    let [r, g, b] = adjusted.map(|value| value.round().clamp(0.0, 255.0) as u8);
+    Rgba::from([r, g, b, color[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FONT: &str = "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf";
+
+    fn get_test_font() -> FontVec {
+        get_font(TEST_FONT, 0).expect("DejaVu Sans should be installed")
+    }
+
+    #[test]
+    fn cell_spans_do_not_drift() {
+        let advance = 7.3;
+        let mut x = 0.0;
+        let mut total_width = 0;
+        for _ in 0..1000 {
+            let (left, width) = get_cell_span(x, advance);
+            assert_eq!(left, total_width);
+            assert!(width == 7 || width == 8);
+            total_width += width;
+            x += advance;
+        }
+        assert_eq!(total_width, (1000.0 * advance).round() as u32);
+    }
+
+    #[test]
+    fn combining_marks_are_dropped_from_text() {
+        let font = get_test_font();
+        let scaled_font = font.as_scaled(20.0);
+        let get_glyph_width = |glyph: char| {
+            let glyph_id = scaled_font.glyph_id(glyph);
+            scaled_font.h_advance(glyph_id) + scaled_font.h_side_bearing(glyph_id)
+        };
+        let text = "Cafe\u{301} na\u{308}i\u{308}ve Z\u{302}\u{323}\u{30c}a\u{301}\u{300}\u{303}lgo\u{327}";
+        assert_eq!(get_drawable_text(text, get_glyph_width), "Cafe naive Zalgo");
+    }
+
+    #[test]
+    fn average_color_rounds_unless_legacy() {
+        let mut image = RgbaImage::new(2, 1);
+        image.put_pixel(0, 0, Rgba::from([200, 200, 200, 255]));
+        image.put_pixel(1, 0, Rgba::from([201, 201, 201, 255]));
+        let image = DynamicImage::ImageRgba8(image);
+        assert_eq!(
+            get_average_color(&image, 1, false),
+            Rgba::from([201, 201, 201, 255])
+        );
+        assert_eq!(
+            get_average_color(&image, 1, true),
+            Rgba::from([200, 200, 200, 255])
+        );
+    }
+
+    #[test]
+    fn kerning_pulls_kerned_pairs_together() {
+        let font = get_test_font();
+        let scaled_font = font.as_scaled(40.0);
+        let get_text_width = |text: &str, is_kerned: bool| {
+            let mut previous = None;
+            let mut x = 0.0;
+            for glyph in text.chars() {
+                if is_kerned {
+                    x += get_kerning(Some(&scaled_font), previous, glyph);
+                }
+                x += scaled_font.h_advance(scaled_font.glyph_id(glyph));
+                previous = Some(glyph);
+            }
+            x
+        };
+        assert!(get_text_width("AV", true) < get_text_width("AV", false));
+        assert_eq!(get_kerning(Some(&scaled_font), None, 'V'), 0.0);
+        assert_eq!(
+            get_kerning(None::<&ab_glyph::PxScaleFont<&FontVec>>, Some('A'), 'V'),
+            0.0
+        );
+    }
+
+    #[test]
+    fn shape_match_picks_the_glyph_covering_the_ink() {
+        // dark ink in the left two columns of a light 4x4 cell
+        let luma_image = GrayImage::from_fn(4, 4, |x, _| Luma([if x < 2 { 0 } else { 255 }]));
+        let get_column_mask = |columns: std::ops::Range<u32>| GlyphMask {
+            mask: GrayImage::from_fn(4, 4, |x, _| {
+                Luma([if columns.contains(&x) { 255 } else { 0 }])
+            }),
+            left: 0,
+            top: 0,
+        };
+        let mut glyph_masks = HashMap::new();
+        glyph_masks.insert('l', Some(get_column_mask(0..2)));
+        glyph_masks.insert('r', Some(get_column_mask(2..4)));
+        let candidates = [('r', 4), ('l', 4)];
+
+        assert_eq!(
+            get_shape_matched_glyph(&luma_image, 0, 0, 4, &candidates, &glyph_masks, false),
+            'l'
+        );
+        // on a dark background the light part is the ink
+        assert_eq!(
+            get_shape_matched_glyph(&luma_image, 0, 0, 4, &candidates, &glyph_masks, true),
+            'r'
+        );
+    }
+
+    #[test]
+    fn jpeg_comment_follows_the_jfif_header() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba::from([10, 20, 30, 255]));
+        let mut data = Vec::new();
+        write_jpeg(&image, &mut data, Some("hello")).expect("jpeg should encode");
+
+        // start of image, then the APP0 segment required by JFIF
+        assert_eq!(data[0..4], [0xFF, 0xD8, 0xFF, 0xE0]);
+        let position = 4 + u16::from_be_bytes([data[4], data[5]]) as usize;
+        assert_eq!(data[position..position + 4], [0xFF, 0xFE, 0x00, 0x07]);
+        assert_eq!(&data[position + 4..position + 9], b"hello");
+
+        let mut plain = Vec::new();
+        write_jpeg(&image, &mut plain, None).expect("jpeg should encode");
+        assert_eq!(plain.len() + 9, data.len());
+    }
+
+    #[test]
+    fn charset_map_bands_are_sorted_by_threshold() {
+        let bands = get_charset_map("light:binary, 64:decimal,dark:braille", false, None)
+            .expect("map should parse");
+        let thresholds: Vec<f32> = bands.iter().map(|(threshold, _)| *threshold).collect();
+        assert_eq!(thresholds, [0.0, 64.0, 128.0]);
+        assert_eq!(bands[0].1[0], '\u{2800}');
+        assert_eq!(bands[1].1.len(), 10);
+        assert_eq!(bands[2].1, ['0', '1']);
+
+        assert!(get_charset_map("dark", false, None).is_err());
+        assert!(get_charset_map("dim:latin", false, None).is_err());
+        assert!(get_charset_map("dark:klingon", false, None).is_err());
+    }
+
+    #[test]
+    fn font_charset_drops_combining_marks() {
+        let font = get_test_font();
+        let scaled_font = font.as_scaled(20.0);
+        let characters = get_characters(Charset::Font, true, Some(&font));
+        assert!(characters.contains(&'\u{301}'));
+        assert!(get_glyph_advance(&scaled_font, '\u{301}') < 1.0);
+
+        let drawable: Vec<char> = characters
+            .into_iter()
+            .filter(|c| get_glyph_advance(&scaled_font, *c) >= 1.0)
+            .collect();
+        assert!(!drawable.contains(&'\u{301}'));
+        assert!(drawable.contains(&'a'));
+    }
+
+    #[test]
+    fn ass_subtitles_escape_tags_and_reverse_colors() {
+        let glyphs = [
+            (1, 2, '{', Rgba([0x11, 0x22, 0x33, 0xFF])),
+            (3, 4, '\\', Rgba([0xAA, 0xBB, 0xCC, 0x00])),
+            (5, 6, 'a', Rgba([0x01, 0x02, 0x03, 0x80])),
+        ];
+        let subtitles = get_ass_subtitles(&glyphs, 10, 20, "/fonts/Example.ttf", 12.0);
+        assert!(subtitles.contains("PlayResX: 10\nPlayResY: 20\n"));
+        assert!(subtitles.contains("Style: Default,Example,12,"));
+
+        let dialogues: Vec<&str> = subtitles
+            .lines()
+            .filter(|line| line.starts_with("Dialogue: "))
+            .collect();
+        assert_eq!(
+            dialogues,
+            [
+                "Dialogue: 0,0:00:00.00,9:59:59.99,Default,{\\pos(1,2)\\1c&H332211&\\1a&H00&}\\{",
+                "Dialogue: 0,0:00:00.00,9:59:59.99,Default,{\\pos(3,4)\\1c&HCCBBAA&\\1a&HFF&}\\\\",
+                "Dialogue: 0,0:00:00.00,9:59:59.99,Default,{\\pos(5,6)\\1c&H030201&\\1a&H7F&}a",
+            ]
+        );
+    }
+
+    #[test]
+    fn kmeans_palette_stays_within_max_colors() {
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(64, 64, |x, y| {
+            Rgba([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8, 255])
+        }));
+        let mut rng = StdRng::seed_from_u64(0);
+        for max_colors in [1, 2, 8, 16] {
+            let palette = get_kmeans_palette(&image, max_colors, &mut rng);
+            assert!(!palette.is_empty());
+            assert!(palette.len() <= max_colors as usize);
+
+            let color = Rgba([10, 200, 30, 77]);
+            let nearest = get_nearest_palette_color(&color, &palette);
+            assert_eq!(nearest[3], 77);
+            assert!(palette.iter().any(|entry| entry.0[..3] == nearest.0[..3]));
+        }
+    }
+
+    #[test]
+    fn template_indices_unpack_low_bit_depths() {
+        const WIDTH: u32 = 7;
+        const HEIGHT: u32 = 3;
+
+        for bit_depth in [
+            png::BitDepth::One,
+            png::BitDepth::Two,
+            png::BitDepth::Four,
+            png::BitDepth::Eight,
+        ] {
+            let bits = bit_depth as u32;
+            let colors = 1 << bits;
+            let get_index = |x: u32, y: u32| ((x + y * WIDTH) % colors) as u8;
+
+            // packs the pixels of every line from the highest bit on
+            let line_size = (WIDTH * bits).div_ceil(8) as usize;
+            let mut data = vec![0u8; line_size * HEIGHT as usize];
+            for y in 0..HEIGHT {
+                for x in 0..WIDTH {
+                    let bit = (x * bits) as usize;
+                    let shift = 8 - bits as usize - bit % 8;
+                    data[y as usize * line_size + bit / 8] |= get_index(x, y) << shift;
+                }
+            }
+
+            let mut png_data = Vec::new();
+            let mut encoder = png::Encoder::new(&mut png_data, WIDTH, HEIGHT);
+            encoder.set_color(png::ColorType::Indexed);
+            encoder.set_depth(bit_depth);
+            encoder.set_palette(vec![0; 3 * colors as usize]);
+            let mut writer = encoder.write_header().unwrap();
+            writer.write_image_data(&data).unwrap();
+            writer.finish().unwrap();
+
+            let indices = get_template_indices(&png_data).unwrap();
+            assert_eq!(indices.dimensions(), (WIDTH, HEIGHT));
+            for (x, y, index) in indices.enumerate_pixels() {
+                assert_eq!(index[0], get_index(x, y), "{bits} bits at {x},{y}");
+            }
+        }
+    }
+}